@@ -0,0 +1,208 @@
+use std::{collections::BTreeSet, process::Command};
+
+use crate::{info, pacman, privilege, run, Context, Escalation, Path, PathBuf, Result, RunAs};
+
+/// Where AUR repos are cloned to, by the makepkg backend and by `--download-first`.
+pub fn repos_dir() -> PathBuf {
+	privilege::home_dir()
+		.unwrap_or_else(std::env::temp_dir)
+		.join(".cache")
+		.join("mrow")
+		.join("aur")
+		.join("repos")
+}
+
+/// Clones the AUR repo of a package, or fetches it if it was cloned before. The repo is checked out at the commit in
+/// `mrow.lock`, or the latest one if the package isn't locked. Repos are only fetched once per run. Returns where the
+/// repo is.
+pub fn fetch_repo(ctx: &Context, owner: &Path, run_as: &RunAs, package: &str) -> Result<PathBuf> {
+	let repo = repos_dir().join(package);
+	if ctx.aur_fetched.borrow().contains(package) {
+		return Ok(repo);
+	}
+	let mut git = ctx.privilege.command(run_as, "git");
+	let checkout = if repo.exists() {
+		git.arg("-C").arg(&repo).args(["fetch", "origin", "HEAD"]);
+		Some(ctx.aur_pins.get(package).map_or("FETCH_HEAD", String::as_str))
+	} else {
+		git.arg("clone")
+			.arg(format!("https://aur.archlinux.org/{package}.git"))
+			.arg(&repo);
+		ctx.aur_pins.get(package).map(String::as_str)
+	};
+	run(ctx, owner, git)?;
+
+	if let Some(commit) = checkout {
+		let mut git = ctx.privilege.command(run_as, "git");
+		git.arg("-C")
+			.arg(&repo)
+			.args(["checkout", "--quiet", "--detach", commit]);
+		run(ctx, owner, git)?;
+	}
+	ctx.aur_fetched.borrow_mut().insert(package.to_string());
+	Ok(repo)
+}
+
+/// The commit of the repo mrow cloned for a package, if it's checked out at the installed `version`.
+pub fn built_commit(package: &str, version: &str) -> Option<String> {
+	let repo = repos_dir().join(package);
+	let srcinfo = std::fs::read_to_string(repo.join(".SRCINFO")).ok()?;
+	if self::version(&srcinfo).as_deref() != Some(version) {
+		return None;
+	}
+
+	let output = Command::new("git")
+		.arg("-C")
+		.arg(&repo)
+		.args(["rev-parse", "HEAD"])
+		.output()
+		.ok()?;
+	output
+		.status
+		.success()
+		.then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// The packages needed to build and install a package, read from its `.SRCINFO`. Versions are stripped and
+/// architecture specific dependencies of other architectures are left out.
+pub fn dependencies(srcinfo: &str) -> BTreeSet<String> {
+	let keys = ["depends", "makedepends", "checkdepends"];
+	srcinfo
+		.lines()
+		.filter_map(|line| line.trim().split_once(" = "))
+		.filter(|(key, _)| {
+			keys.iter().any(|prefix| {
+				key.strip_prefix(prefix)
+					.is_some_and(|arch| arch.is_empty() || arch.strip_prefix('_') == Some(std::env::consts::ARCH))
+			})
+		})
+		.map(|(_, value)| pacman::without_version(value).to_string())
+		.collect()
+}
+
+/// The names of the packages a `.SRCINFO` builds, more than one for split packages.
+pub fn package_names(srcinfo: &str) -> Vec<String> {
+	srcinfo
+		.lines()
+		.filter_map(|line| line.strip_prefix("pkgname = "))
+		.map(str::to_string)
+		.collect()
+}
+
+/// The `pkgbase` of a `.SRCINFO`, which split packages share.
+pub fn package_base(srcinfo: &str) -> Option<&str> {
+	srcinfo.lines().find_map(|line| line.strip_prefix("pkgbase = "))
+}
+
+/// The version a `.SRCINFO` builds, formatted like pacman does: `[epoch:]pkgver-pkgrel`.
+pub fn version(srcinfo: &str) -> Option<String> {
+	let field = |key: &str| {
+		srcinfo
+			.lines()
+			.filter_map(|line| line.trim().split_once(" = "))
+			.find_map(|(k, value)| (k == key).then_some(value))
+	};
+	let version = format!("{}-{}", field("pkgver")?, field("pkgrel")?);
+	Some(match field("epoch") {
+		Some(epoch) if epoch != "0" => format!("{epoch}:{version}"),
+		_ => version,
+	})
+}
+
+/// Fetches the repos of AUR packages and of the AUR packages they depend on, and downloads their sources to
+/// `ctx.aur_sources`, where makepkg finds them when the packages are built. Returns the repo packages they depend on
+/// that aren't installed yet, so they can be downloaded as well.
+pub fn download_packages(ctx: &Context, owner: &Path, packages: &[String]) -> Result<BTreeSet<String>> {
+	let Some(ref sources) = ctx.aur_sources else {
+		return Ok(BTreeSet::new());
+	};
+	let build = Build::resolve_all(ctx, owner, &RunAs::User, packages)?;
+	for (repo, _) in &build.order {
+		info!(
+			"Downloading sources of AUR package {}",
+			repo.file_name().unwrap_or_default().to_string_lossy()
+		);
+		let mut makepkg = ctx.privilege.user_command("makepkg");
+		makepkg.arg("--verifysource").current_dir(repo).env("SRCDEST", sources);
+		run(ctx, owner, makepkg)?;
+	}
+	Ok(build.repo_dependencies)
+}
+
+/// Installs AUR packages without an AUR helper. Their repos are cloned and built with `makepkg -si`, after the AUR
+/// packages they depend on. makepkg installs repo dependencies by itself.
+pub fn install_packages(ctx: &Context, owner: &Path, run_as: &RunAs, packages: &[String]) -> Result<()> {
+	let build = Build::resolve_all(ctx, owner, run_as, packages)?;
+
+	for (repo, explicit) in build.order {
+		let mut makepkg = ctx.privilege.command(run_as, "makepkg");
+		makepkg.args(["-si", "--noconfirm", "--needed"]).current_dir(&repo);
+		if !explicit {
+			makepkg.arg("--asdeps");
+		}
+		if let Escalation::Doas | Escalation::Run0 = ctx.privilege.escalation {
+			makepkg.env("PACMAN_AUTH", ctx.privilege.escalation.name());
+		}
+		if let Some(ref sources) = ctx.aur_sources {
+			makepkg.env("SRCDEST", sources);
+		}
+		run(ctx, owner, makepkg)?;
+	}
+	Ok(())
+}
+
+struct Build<'a> {
+	ctx: &'a Context,
+	owner: &'a Path,
+	run_as: &'a RunAs,
+	/// The names of all AUR packages
+	aur: BTreeSet<String>,
+	seen: BTreeSet<String>,
+	/// Repos in the order they're built in, and whether the package was asked for or is a dependency
+	order: Vec<(PathBuf, bool)>,
+	/// Dependencies from the repos that aren't installed, makepkg installs them
+	repo_dependencies: BTreeSet<String>,
+}
+
+impl<'a> Build<'a> {
+	/// Fetches the repos of `packages` and the AUR packages they depend on.
+	fn resolve_all(ctx: &'a Context, owner: &'a Path, run_as: &'a RunAs, packages: &[String]) -> Result<Self> {
+		let mut build = Build {
+			ctx,
+			owner,
+			run_as,
+			aur: pacman::aur_packages()?,
+			seen: BTreeSet::new(),
+			order: vec![],
+			repo_dependencies: BTreeSet::new(),
+		};
+		for package in packages {
+			build.resolve(package, true)?;
+		}
+		Ok(build)
+	}
+
+	fn resolve(&mut self, package: &str, explicit: bool) -> Result<()> {
+		if !self.seen.insert(package.to_string()) {
+			return Ok(());
+		}
+
+		let repo = fetch_repo(self.ctx, self.owner, self.run_as, package)?;
+		// Only missing when the clone was skipped with --debug
+		if let Ok(srcinfo) = std::fs::read_to_string(repo.join(".SRCINFO")) {
+			for dependency in dependencies(&srcinfo) {
+				if self.ctx.installed.is_installed(&dependency) {
+					continue;
+				}
+				if self.aur.contains(&dependency) {
+					info!("{package} depends on AUR package {dependency}");
+					self.resolve(&dependency, false)?;
+				} else {
+					self.repo_dependencies.insert(dependency);
+				}
+			}
+		}
+		self.order.push((repo, explicit));
+		Ok(())
+	}
+}
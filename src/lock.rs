@@ -0,0 +1,144 @@
+use std::{collections::BTreeMap, process::Command};
+
+use serde::Serialize;
+
+use crate::{
+	aur, info,
+	pacman::{LocalDb, SyncDb},
+	warn, Deserialize, Error, Path, PathBuf, Result,
+};
+
+const HEADER: &str = "# Written by `mrow lock`, the versions of every package in the config\n\n";
+
+/// The versions packages were at when `mrow lock` was run, kept in `mrow.lock` next to the root config.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Lockfile {
+	#[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+	pub packages: BTreeMap<String, String>,
+	#[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+	pub aur: BTreeMap<String, AurPin>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct AurPin {
+	pub version: String,
+	/// The commit of the package's AUR repo, so changes to the PKGBUILD are noticed even if the version stays the same
+	pub commit: String,
+}
+
+impl Lockfile {
+	pub fn path(base_dir: &Path) -> PathBuf {
+		base_dir.join("mrow.lock")
+	}
+
+	pub fn load(base_dir: &Path) -> Result<Option<Self>> {
+		let path = Self::path(base_dir);
+		if !path.exists() {
+			return Ok(None);
+		}
+		toml::from_str(&std::fs::read_to_string(&path)?)
+			.map(Some)
+			.map_err(|err| Error::Toml(path, err))
+	}
+
+	pub fn save(&self, base_dir: &Path) -> Result<()> {
+		let lock = toml::to_string(self).unwrap_or_else(|_| unreachable!("lockfiles only contain strings"));
+		std::fs::write(Self::path(base_dir), format!("{HEADER}{lock}"))?;
+		Ok(())
+	}
+
+	/// Describes every way the packages differ from the lockfile. Packages that aren't installed are compared with the
+	/// version in the sync databases, if they could be read.
+	///
+	/// With `aur_pinned`, AUR packages are built from the locked commit and installed ones are compared by the commit
+	/// they were built from. Otherwise the AUR helper builds the latest commit, changes to the AUR repo are only logged.
+	pub fn deviations(
+		&self,
+		packages: &[(&String, bool)],
+		installed: &LocalDb,
+		sync: Option<&SyncDb>,
+		aur_pinned: bool,
+	) -> Vec<String> {
+		let mut deviations = vec![];
+		for &(package, aur) in packages {
+			let locked = if aur {
+				self.aur.get(package).map(|lock| &lock.version)
+			} else {
+				self.packages.get(package)
+			};
+			let Some(locked) = locked else {
+				// Groups and provided names can't be locked
+				let is_package =
+					installed.get(package).is_some() || sync.and_then(|sync| sync.version(package)).is_some();
+				if is_package {
+					deviations.push(format!("'{package}' isn't in mrow.lock"));
+				}
+				continue;
+			};
+
+			if let Some(local) = installed.get(package) {
+				if local.version != *locked {
+					deviations.push(format!(
+						"'{package}' {} is installed, mrow.lock has {locked}",
+						local.version
+					));
+				}
+			} else if let Some(version) = sync.and_then(|sync| sync.version(package)) {
+				if version != locked {
+					deviations.push(format!(
+						"'{package}' would be installed at {version}, mrow.lock has {locked}"
+					));
+				}
+			}
+
+			let Some(lock) = self.aur.get(package).filter(|_| aur) else {
+				continue;
+			};
+			if aur_pinned {
+				let built = installed
+					.get(package)
+					.and_then(|local| aur::built_commit(package, &local.version));
+				if let Some(commit) = built.filter(|commit| *commit != lock.commit) {
+					deviations.push(format!(
+						"AUR package '{package}' was built from {} instead of {}",
+						short(&commit),
+						short(&lock.commit)
+					));
+				}
+				continue;
+			}
+			match aur_commit(package) {
+				Ok(commit) if commit != lock.commit => info!(
+					"AUR package '{package}' changed since it was locked, its repo is at {} instead of {}. The AUR \
+					 helper builds the latest commit",
+					short(&commit),
+					short(&lock.commit)
+				),
+				Ok(_) => {}
+				Err(err) => warn!("{err}"),
+			}
+		}
+		deviations
+	}
+}
+
+fn short(commit: &str) -> &str {
+	commit.get(..10).unwrap_or(commit)
+}
+
+/// The commit the AUR repo of a package is currently at.
+pub fn aur_commit(package: &str) -> Result<String> {
+	let output = Command::new("git")
+		.arg("ls-remote")
+		.arg(format!("https://aur.archlinux.org/{package}.git"))
+		.arg("HEAD")
+		.output()?;
+	let stdout = String::from_utf8_lossy(&output.stdout);
+	match stdout.split_whitespace().next() {
+		Some(commit) if output.status.success() => Ok(commit.to_string()),
+		_ => Err(Error::AurCommit(
+			package.into(),
+			String::from_utf8_lossy(&output.stderr).trim().to_string(),
+		)),
+	}
+}
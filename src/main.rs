@@ -1,22 +1,39 @@
 #![warn(clippy::pedantic)]
 #![allow(clippy::too_many_lines)]
 
+mod aur;
+mod lock;
 mod mrow_lua;
 mod mrow_toml;
+mod pacman;
+mod privilege;
+mod prompts;
+mod secrets;
+mod sudo;
 
 use std::{
+	cell::{Cell, OnceCell, RefCell},
+	collections::{BTreeMap, BTreeSet},
 	env::VarError,
-	ffi::OsStr,
+	io::{BufRead, Write},
+	os::unix::{fs::OpenOptionsExt, process::CommandExt},
 	path::{Path, PathBuf},
-	process::exit,
+	process::{exit, Output, Stdio},
 	rc::Rc,
-	sync::{LazyLock, Mutex},
+	sync::{
+		atomic::{AtomicUsize, Ordering},
+		mpsc, LazyLock, Mutex,
+	},
+	time::{Duration, Instant},
 };
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use lock::{AurPin, Lockfile};
 use log::{debug, error, info, warn};
 use miette::IntoDiagnostic;
 use mlua::{Lua, StdLib};
+use pacman::{InstallReason, LocalDb, SyncDb};
+use privilege::{Escalation, Privilege, RunAs};
 use regex::Regex;
 use serde::Deserialize;
 use thiserror::Error;
@@ -36,6 +53,49 @@ enum Error {
 
 	#[error("Step in '{0}' failed. {1}")]
 	StepFailed(String, String),
+	#[error("Step in '{0}' timed out after {1}s")]
+	StepTimedOut(String, u64),
+	#[error("Handler '{0}' is defined more than once")]
+	DuplicateHandler(String),
+	#[error("A step in '{0}' notifies handler '{1}' which doesn't exist")]
+	UnknownHandler(String, String),
+	#[error("{0} step(s) failed")]
+	StepsFailed(usize),
+	#[error("A step in '{0}' removes package '{1}' which is also installed by a step")]
+	RemovedPackageInstalled(String, String),
+
+	#[error("Couldn't read package list '{0}'. {1}")]
+	PackageList(String, String),
+	#[error("{0} package(s) can't be installed as configured")]
+	UnknownPackages(usize),
+	#[error("Couldn't get the AUR commit of '{0}'. {1}")]
+	AurCommit(String, String),
+	#[error("{0} package(s) deviate from mrow.lock")]
+	LockDeviations(usize),
+	#[error("No AUR helper is set in your config")]
+	NoAurHelper,
+	#[error("The AUR repo of '{0}' is at {1}, not at the pinned commit {2}")]
+	BootstrapCommit(String, String, String),
+	#[error("Building '{0}' was declined after reviewing its PKGBUILD")]
+	BootstrapDeclined(String),
+	#[error("The AUR helper {0} always uses sudo and can't be used with {1}. Use yay, paru or makepkg instead")]
+	AurHelperEscalation(&'static str, &'static str),
+
+	#[error("sudo credentials expired and couldn't be refreshed. Re-run mrow to authenticate again")]
+	SudoExpired,
+	#[error(
+		"doas didn't keep the password after authenticating. mrow needs 'persist' in /etc/doas.conf, e.g. 'permit \
+		 persist :wheel'"
+	)]
+	DoasNotPersisted,
+
+	#[error("Invalid prompt '{0}'. {1}")]
+	InvalidPrompt(String, String),
+
+	#[error("No age identity configured. Set 'age-identity' in your config or pass --age-identity")]
+	NoAgeIdentity,
+	#[error("age failed for '{0}'. {1}")]
+	Age(PathBuf, String),
 
 	#[error("'{0}': {1}")]
 	Toml(PathBuf, toml::de::Error),
@@ -49,11 +109,173 @@ enum Error {
 
 type Result<T> = miette::Result<T, Error>;
 
-#[derive(Debug, Deserialize, Clone, Copy)]
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
 #[serde(rename_all = "lowercase")]
 enum AurHelper {
 	Yay,
 	Paru,
+	Pikaur,
+	Aura,
+	Trizen,
+	/// No helper, mrow clones AUR repos and builds them with makepkg itself
+	Makepkg,
+}
+
+impl AurHelper {
+	/// The AUR package the helper is bootstrapped from, `None` for makepkg which comes with pacman.
+	fn package(self) -> Option<&'static str> {
+		match self {
+			AurHelper::Yay => Some("yay"),
+			AurHelper::Paru => Some("paru-bin"),
+			AurHelper::Pikaur => Some("pikaur"),
+			AurHelper::Aura => Some("aura-bin"),
+			AurHelper::Trizen => Some("trizen"),
+			AurHelper::Makepkg => None,
+		}
+	}
+
+	/// The helper's executable, which every package of the helper provides.
+	fn binary(self) -> &'static str {
+		match self {
+			AurHelper::Yay => "yay",
+			AurHelper::Paru => "paru",
+			AurHelper::Pikaur => "pikaur",
+			AurHelper::Aura => "aura",
+			AurHelper::Trizen => "trizen",
+			AurHelper::Makepkg => "makepkg",
+		}
+	}
+
+	/// Whether the helper or the packages needed to build AUR packages have to be installed first.
+	fn needs_bootstrap(self, installed: &LocalDb) -> bool {
+		(self.package().is_some() && !installed.is_installed(self.binary())) || !has_aur_prerequisites(installed)
+	}
+}
+
+/// How the AUR helper is installed when it isn't installed yet.
+#[derive(Debug, Default, Deserialize, Clone)]
+#[serde(rename_all = "kebab-case")]
+struct AurBootstrap {
+	/// The AUR package the helper is built from instead of the default one, e.g. 'paru' instead of 'paru-bin'
+	package: Option<String>,
+	/// The helper's AUR repo is cloned into a directory named after the package in here, '/opt' by default
+	build_dir: Option<PathBuf>,
+	#[serde(default)]
+	cleanup: BootstrapCleanup,
+	/// Show the PKGBUILD and ask before building it
+	#[serde(default)]
+	review: bool,
+	/// The commit the helper's AUR repo has to be at, the bootstrap fails if it's at another one
+	commit: Option<String>,
+}
+
+impl AurBootstrap {
+	/// The AUR package the helper is built from, `None` for makepkg.
+	fn package(&self, aur_helper: AurHelper) -> Option<&str> {
+		aur_helper
+			.package()
+			.map(|package| self.package.as_deref().unwrap_or(package))
+	}
+
+	/// The AUR package the helper is built from, if the helper isn't installed yet.
+	fn missing(&self, aur_helper: AurHelper, installed: &LocalDb) -> Option<&str> {
+		self.package(aur_helper)
+			.filter(|_| !installed.is_installed(aur_helper.binary()))
+	}
+}
+
+/// When the AUR helper's build directory is removed after bootstrapping it.
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+enum BootstrapCleanup {
+	/// Keep it
+	#[default]
+	Never,
+	/// Remove it if the helper was installed, so a failed build can be looked into
+	OnSuccess,
+	Always,
+}
+
+/// How the package databases are refreshed at the start of a run. Package steps never refresh them on their own.
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum SyncPolicy {
+	/// Refresh and upgrade the whole system, `pacman -Syu`
+	#[default]
+	Upgrade,
+	/// Only refresh the databases, `pacman -Sy`. Installing packages afterwards is a partial upgrade
+	Sync,
+	/// Use the databases as they are
+	Never,
+}
+
+/// What happens to explicitly installed packages that no package step lists.
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum UnlistedPackages {
+	/// Leave them alone
+	#[default]
+	Keep,
+	/// Mark them as installed as a dependency, so they're removed along with other orphans
+	Mark,
+	/// Uninstall them along with dependencies nothing else needs, `pacman -Rs`
+	Remove,
+}
+
+/// What happens when packages deviate from `mrow.lock`.
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum LockPolicy {
+	/// Don't compare packages with the lockfile
+	#[default]
+	Ignore,
+	/// List the deviations and carry on
+	Report,
+	/// List the deviations and stop before anything is changed
+	Fail,
+}
+
+#[derive(Debug, Default, Clone)]
+struct Config {
+	aur_helper: Option<AurHelper>,
+	sync_policy: SyncPolicy,
+	lock_policy: LockPolicy,
+	unlisted_packages: UnlistedPackages,
+	/// Explicitly installed packages that are kept even though no package step lists them
+	ignored_packages: Vec<String>,
+	aur_bootstrap: AurBootstrap,
+	escalation: Option<Escalation>,
+	age_identity: Option<PathBuf>,
+	/// Passed to every command and script
+	env: BTreeMap<String, String>,
+	/// Used for steps that don't set their own
+	timeout: Option<Duration>,
+	/// Used for steps that don't set their own
+	retries: Option<u32>,
+}
+
+/// Options every step kind accepts.
+#[derive(Debug, Clone, Default)]
+struct StepOptions {
+	run_as: RunAs,
+	/// Handlers to run at the end if this step changed something
+	notify: Vec<String>,
+	timeout: Option<Duration>,
+	/// How often to retry the step if it fails or times out
+	retries: Option<u32>,
+	/// Carry on with the run if this step fails
+	ignore_errors: bool,
+}
+
+/// Conditions that skip a command or script step, checked in this order.
+#[derive(Debug, Clone, Default)]
+struct Guard {
+	/// Skip the step if this path exists
+	creates: Option<PathBuf>,
+	/// Skip the step if this command succeeds
+	unless: Option<String>,
+	/// Only run the step if this command succeeds
+	onlyif: Option<String>,
 }
 
 #[derive(Debug, Clone)]
@@ -61,6 +283,7 @@ struct Step {
 	owner: PathBuf,
 	relative_path_str: String,
 	kind: StepKind,
+	options: StepOptions,
 }
 
 #[derive(Debug, Clone)]
@@ -73,10 +296,41 @@ enum StepKind {
 		packages: Vec<String>,
 		aur: bool,
 	},
+	/// Removes the package if it's installed, with its unneeded dependencies (`-s`) and without saving changed
+	/// config files (`-n`) if set
+	RemovePackage {
+		package: String,
+		dependencies: bool,
+		config_files: bool,
+	},
+	RemovePackages {
+		packages: Vec<String>,
+		dependencies: bool,
+		config_files: bool,
+	},
+	/// Builds the PKGBUILD in `path` with makepkg and installs the result with pacman
+	LocalPackage {
+		path: PathBuf,
+	},
+	/// Adds or updates the `[name]` section of pacman.conf
+	PacmanRepo {
+		name: String,
+		servers: Vec<String>,
+		/// A mirrorlist file
+		include: Option<String>,
+		sig_level: Option<String>,
+		/// The fingerprint of a key to import and locally sign
+		key: Option<String>,
+		/// Where the key is imported from instead of pacman-key's default keyserver
+		keyserver: Option<String>,
+	},
 	CopyFile {
 		from: PathBuf,
 		to: PathBuf,
-		as_root: bool,
+	},
+	SecretFile {
+		from: PathBuf,
+		to: PathBuf,
 	},
 	Symlink {
 		from: PathBuf,
@@ -85,17 +339,324 @@ enum StepKind {
 	},
 	RunCommand {
 		command: String,
+		env: BTreeMap<String, String>,
+		cwd: Option<PathBuf>,
+		guard: Guard,
 	},
 	RunCommands {
 		commands: Vec<String>,
+		env: BTreeMap<String, String>,
+		cwd: Option<PathBuf>,
+		guard: Guard,
 	},
 	RunScript {
 		path: PathBuf,
+		/// Overrides the script's shebang
+		interpreter: Option<String>,
+		args: Vec<String>,
+		env: BTreeMap<String, String>,
+		/// Defaults to the script's directory
+		cwd: Option<PathBuf>,
+		guard: Guard,
 	},
+	InlineScript {
+		body: String,
+		/// Defaults to `sh`
+		interpreter: Option<String>,
+		env: BTreeMap<String, String>,
+		/// Defaults to the module's directory
+		cwd: Option<PathBuf>,
+		guard: Guard,
+	},
+}
+
+impl StepKind {
+	/// A short description of the step for the summary at the end of a run.
+	fn describe(&self) -> String {
+		match self {
+			StepKind::InstallPackage { package, .. } => format!("install package '{package}'"),
+			StepKind::InstallPackages { packages, .. } => format!("install packages '{}'", packages.join(" ")),
+			StepKind::RemovePackage { package, .. } => format!("remove package '{package}'"),
+			StepKind::RemovePackages { packages, .. } => format!("remove packages '{}'", packages.join(" ")),
+			StepKind::LocalPackage { path } => format!("local package '{}'", path.to_string_lossy()),
+			StepKind::PacmanRepo { name, .. } => format!("pacman repo '{name}'"),
+			StepKind::CopyFile { to, .. } => format!("copy file to '{}'", to.to_string_lossy()),
+			StepKind::SecretFile { to, .. } => format!("decrypt secret to '{}'", to.to_string_lossy()),
+			StepKind::Symlink { to, .. } => format!("symlink '{}'", to.to_string_lossy()),
+			StepKind::RunCommand { command, .. } => format!("command '{command}'"),
+			StepKind::RunCommands { commands, .. } => format!("commands '{}'", commands.join("; ")),
+			StepKind::RunScript { path, .. } => format!("script '{}'", path.to_string_lossy()),
+			StepKind::InlineScript { .. } => "inline script".into(),
+		}
+	}
+}
+
+/// Everything a config defines to run.
+#[derive(Debug, Default)]
+struct Plan {
+	steps: Vec<Step>,
+	/// Named step lists that run once at the end of the run, if a step notifying them changed something
+	handlers: BTreeMap<String, Vec<Step>>,
+	/// Steps that run before the first step of a module, by module path
+	before: BTreeMap<PathBuf, Vec<Step>>,
+	/// Steps that run after the last step of a module, by module path
+	after: BTreeMap<PathBuf, Vec<Step>>,
+	hooks: Hooks,
+}
+
+/// Steps that run around the whole run.
+#[derive(Debug, Default)]
+struct Hooks {
+	pre_run: Vec<Step>,
+	/// Runs whether the run failed or not
+	post_run: Vec<Step>,
+	on_failure: Vec<Step>,
+}
+
+impl Plan {
+	/// All steps, including the ones of handlers and hooks.
+	fn all_steps(&self) -> impl Iterator<Item = &Step> {
+		self.steps
+			.iter()
+			.chain(self.handlers.values().flatten())
+			.chain(self.before.values().flatten())
+			.chain(self.after.values().flatten())
+			.chain(&self.hooks.pre_run)
+			.chain(&self.hooks.post_run)
+			.chain(&self.hooks.on_failure)
+	}
+
+	fn add_handler(&mut self, name: String, steps: Vec<Step>) -> Result<()> {
+		if self.handlers.contains_key(&name) {
+			return Err(Error::DuplicateHandler(name));
+		}
+		self.handlers.insert(name, steps);
+		Ok(())
+	}
+
+	/// Makes sure every handler that's notified exists.
+	fn check_handlers(&self) -> Result<()> {
+		for step in self.all_steps() {
+			if let Some(name) = step.options.notify.iter().find(|n| !self.handlers.contains_key(*n)) {
+				return Err(Error::UnknownHandler(step.relative_path_str.clone(), name.clone()));
+			}
+		}
+		Ok(())
+	}
+
+	/// Makes sure no package is both installed and removed.
+	fn check_removals(&self) -> Result<()> {
+		let installed = self
+			.all_steps()
+			.filter_map(step_packages)
+			.flat_map(|(packages, _)| packages)
+			.collect::<BTreeSet<_>>();
+		for step in self.all_steps() {
+			let removed = match &step.kind {
+				StepKind::RemovePackage { package, .. } => std::slice::from_ref(package),
+				StepKind::RemovePackages { packages, .. } => packages,
+				_ => continue,
+			};
+			if let Some(package) = removed.iter().find(|package| installed.contains(package)) {
+				return Err(Error::RemovedPackageInstalled(
+					step.relative_path_str.clone(),
+					package.clone(),
+				));
+			}
+		}
+		Ok(())
+	}
+
+	/// Merges package steps next to each other into one pacman transaction and one AUR helper invocation.
+	///
+	/// Modules with before or after steps aren't merged with other modules, those have to run around the module's own
+	/// steps.
+	fn merge_package_steps(&mut self) {
+		let barriers = self
+			.before
+			.keys()
+			.chain(self.after.keys())
+			.cloned()
+			.collect::<BTreeSet<_>>();
+		self.steps = merge_package_steps(std::mem::take(&mut self.steps), &barriers);
+
+		let none = BTreeSet::new();
+		for steps in self
+			.handlers
+			.values_mut()
+			.chain(self.before.values_mut())
+			.chain(self.after.values_mut())
+			.chain([
+				&mut self.hooks.pre_run,
+				&mut self.hooks.post_run,
+				&mut self.hooks.on_failure,
+			]) {
+			*steps = merge_package_steps(std::mem::take(steps), &none);
+		}
+	}
+}
+
+/// The packages of a package step and whether they're from the AUR.
+fn step_packages(step: &Step) -> Option<(&[String], bool)> {
+	match &step.kind {
+		StepKind::InstallPackage { package, aur } => Some((std::slice::from_ref(package), *aur)),
+		StepKind::InstallPackages { packages, aur } => Some((packages, *aur)),
+		_ => None,
+	}
+}
+
+fn can_merge(a: &Step, b: &Step, barriers: &BTreeSet<PathBuf>) -> bool {
+	// A merged step changes something if any of its packages was missing, so only steps notifying the same handlers
+	// can be merged
+	let same_options = a.options.run_as == b.options.run_as
+		&& a.options.notify == b.options.notify
+		&& a.options.timeout == b.options.timeout
+		&& a.options.retries == b.options.retries
+		&& a.options.ignore_errors == b.options.ignore_errors;
+	let same_module = a.owner == b.owner || !(barriers.contains(&a.owner) || barriers.contains(&b.owner));
+	step_packages(a).is_some() && step_packages(b).is_some() && same_options && same_module
+}
+
+/// Merges runs of package steps, steps in between are never moved past.
+fn merge_package_steps(steps: Vec<Step>, barriers: &BTreeSet<PathBuf>) -> Vec<Step> {
+	let mut merged = Vec::with_capacity(steps.len());
+	let mut batch: Vec<Step> = vec![];
+	for step in steps {
+		if !batch.last().is_some_and(|last| can_merge(last, &step, barriers)) {
+			merged.extend(merge_batch(std::mem::take(&mut batch)));
+		}
+		if step_packages(&step).is_some() {
+			batch.push(step);
+		} else {
+			merged.push(step);
+		}
+	}
+	merged.extend(merge_batch(batch));
+	merged
+}
+
+/// Turns a batch of package steps into at most one repo and one AUR step. Repo packages go first, as AUR packages
+/// may depend on them but never the other way around.
+fn merge_batch(batch: Vec<Step>) -> Vec<Step> {
+	let is_repo = |step: &Step| step_packages(step).is_some_and(|(_, aur)| !aur);
+	let repo_count = batch.iter().filter(|step| is_repo(step)).count();
+	if repo_count <= 1 && batch.len() - repo_count <= 1 {
+		// Nothing to merge, keep the original order
+		return batch;
+	}
+
+	let (repo, aur): (Vec<_>, Vec<_>) = batch.into_iter().partition(is_repo);
+
+	[(repo, false), (aur, true)]
+		.into_iter()
+		.filter(|(steps, _)| !steps.is_empty())
+		.map(|(mut steps, aur)| {
+			if steps.len() == 1 {
+				return steps.remove(0);
+			}
+
+			let mut packages = Vec::<String>::new();
+			let mut modules = Vec::<&str>::new();
+			for step in &steps {
+				let (step_packages, _) =
+					step_packages(step).unwrap_or_else(|| unreachable!("only package steps are batched"));
+				for package in step_packages {
+					if !packages.contains(package) {
+						packages.push(package.clone());
+					}
+				}
+				if !modules.contains(&step.relative_path_str.as_str()) {
+					modules.push(&step.relative_path_str);
+				}
+			}
+
+			let relative_path_str = modules.join(", ");
+			info!(
+				"[{relative_path_str}] Merged {} package steps into one {}",
+				steps.len(),
+				if aur {
+					"AUR helper invocation"
+				} else {
+					"pacman transaction"
+				}
+			);
+			Step {
+				owner: steps[0].owner.clone(),
+				relative_path_str,
+				kind: StepKind::InstallPackages { packages, aur },
+				options: steps[0].options.clone(),
+			}
+		})
+		.collect()
+}
+
+/// A step that failed, for the table at the end of a run.
+struct Failure {
+	module: String,
+	step: String,
+	error: String,
+}
+
+impl Failure {
+	fn new(step: &Step, err: &Error) -> Self {
+		let error = match err {
+			// The module is already in its own column
+			Error::StepFailed(_, message) => message.clone(),
+			Error::StepTimedOut(_, secs) => format!("Timed out after {secs}s"),
+			err => err.to_string(),
+		};
+
+		Self {
+			module: step.relative_path_str.clone(),
+			step: step.kind.describe(),
+			error: error
+				.lines()
+				.map(str::trim)
+				.find(|l| !l.is_empty())
+				.unwrap_or_default()
+				.to_string(),
+		}
+	}
+}
+
+fn print_failures(failures: &[Failure]) {
+	let module_width = failures
+		.iter()
+		.map(|f| f.module.len())
+		.max()
+		.unwrap_or_default()
+		.max("MODULE".len());
+	let step_width = failures
+		.iter()
+		.map(|f| f.step.len())
+		.max()
+		.unwrap_or_default()
+		.max("STEP".len());
+
+	error!("Failed steps:");
+	error!("{:module_width$}  {:step_width$}  ERROR", "MODULE", "STEP");
+	for failure in failures {
+		error!(
+			"{:module_width$}  {:step_width$}  {}",
+			failure.module, failure.step, failure.error
+		);
+	}
+}
+
+/// What happened when a step was run.
+#[derive(Debug)]
+enum StepOutcome {
+	/// The step ran and may have changed something
+	Ran,
+	/// There was nothing to do
+	Unchanged,
+	/// The step was skipped, with the reason
+	Skipped(String),
 }
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
+#[allow(clippy::struct_excessive_bools)]
 struct Args {
 	/// The directory where your 'mrow.{toml,luau}' resides. Defaults to CWD
 	#[arg(short, long)]
@@ -108,6 +669,50 @@ struct Args {
 	/// Doesn't execute any commands, just logs them and what they would do.
 	#[arg(long)]
 	debug: bool,
+
+	/// Run the remaining steps when a step fails, and list all failures at the end
+	#[arg(long)]
+	keep_going: bool,
+
+	/// Download every package that will be installed before anything is changed
+	#[arg(long)]
+	download_first: bool,
+
+	/// Keep downloaded packages in this directory instead of pacman's cache, e.g. one shared by several machines
+	#[arg(long)]
+	package_cache: Option<PathBuf>,
+
+	/// Ask every prompt again instead of using cached answers
+	#[arg(long)]
+	reprompt: bool,
+
+	/// The age identity used to decrypt secrets. Overrides 'age-identity' in your config
+	#[arg(long)]
+	age_identity: Option<PathBuf>,
+
+	#[command(subcommand)]
+	command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+	/// Manage age-encrypted secrets
+	Secret {
+		#[command(subcommand)]
+		action: SecretAction,
+	},
+	/// Record the installed versions of every package in the config to 'mrow.lock'
+	Lock,
+	/// Install the AUR helper, and what building AUR packages needs, without running any steps
+	Bootstrap,
+}
+
+#[derive(Subcommand, Debug)]
+enum SecretAction {
+	/// Encrypts a plaintext file to '<file>.age' for the configured recipients
+	Encrypt { file: PathBuf },
+	/// Decrypts a secret, opens it in $EDITOR and re-encrypts it when saved
+	Edit { file: PathBuf },
 }
 
 /// Resolves a given path string to an absolute path.
@@ -121,7 +726,7 @@ fn resolve_path(from_path: &str, base_path: &Path) -> PathBuf {
 
 	// Expand the home directory symbol
 	if resolved_path.starts_with("~/") {
-		if let Some(home_dir) = dirs::home_dir() {
+		if let Some(home_dir) = privilege::home_dir() {
 			let home_str = home_dir.to_string_lossy();
 			resolved_path = PathBuf::from(&*home_str).join(&from_path[2..]);
 		}
@@ -181,36 +786,96 @@ fn check_os_release() -> Result<()> {
 	Ok(())
 }
 
-fn install_packages(
+/// Everything steps need to know about the current run.
+struct Context {
 	debug: bool,
-	owner: &Path,
-	packages: &[String],
-	aur_flag: bool,
+	privilege: Privilege,
 	aur_helper: Option<AurHelper>,
-) -> Result<()> {
-	let (command, extra_args) = if let Some(aur_helper) = aur_helper {
-		match aur_helper {
-			AurHelper::Yay => ("yay", vec!["-Sy"]),
-			AurHelper::Paru => ("paru", if aur_flag { vec!["-Sya"] } else { vec!["-Sy"] }),
-		}
-	} else {
-		("sudo", vec!["pacman", "-Sy"])
+	identity: Option<secrets::Identity>,
+	/// The packages that were installed when the run started
+	installed: LocalDb,
+	/// Passed to pacman as `--cachedir`
+	package_cache: Option<PathBuf>,
+	/// Where AUR sources are downloaded to before anything is changed, passed to makepkg as `SRCDEST`
+	aur_sources: Option<PathBuf>,
+	/// The AUR repo commits in `mrow.lock`, AUR repos cloned by mrow are checked out at these
+	aur_pins: BTreeMap<String, String>,
+	/// The AUR repos fetched this run, they aren't fetched again when they're built
+	aur_fetched: RefCell<BTreeSet<String>>,
+	/// The configured env and the `MROW_*` variables, every command and script gets these.
+	env: BTreeMap<String, String>,
+	/// The variables in `env` that hold secret prompt answers
+	secret_env: BTreeSet<String>,
+	/// When the current step times out, and its timeout
+	deadline: Cell<Option<(Instant, Duration)>>,
+	/// The packages local package steps build, which count as listed when handling unlisted packages
+	local_packages: RefCell<BTreeSet<String>>,
+	/// Set when a pacman repo step changed pacman.conf, the databases are refreshed before packages are installed next
+	repos_changed: Cell<bool>,
+	/// How the databases are refreshed after a pacman repo step
+	sync_policy: SyncPolicy,
+}
+
+/// How long a timed out step gets to exit after being asked to terminate, before it's killed.
+const TIMEOUT_GRACE: Duration = Duration::from_secs(5);
+/// How long to wait before the first retry of a failed step. It doubles for every further retry.
+const RETRY_BACKOFF: Duration = Duration::from_secs(2);
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(60);
+
+fn signal_group(pgid: u32, signal: &str) {
+	// Best effort, the group may already be gone
+	let _ = std::process::Command::new("kill")
+		.arg(format!("-{signal}"))
+		.arg("--")
+		.arg(format!("-{pgid}"))
+		.stderr(Stdio::null())
+		.status();
+}
+
+/// Runs `cmd` to completion. If the current step times out first, the command's process group is terminated.
+fn wait_for(ctx: &Context, owner: &Path, mut cmd: std::process::Command, capture: bool) -> Result<Output> {
+	let stdio = || if capture { Stdio::piped() } else { Stdio::inherit() };
+	// Steps can't read from the terminal, in a background process group that would stop them until they time out
+	cmd.stdin(Stdio::null()).stdout(stdio()).stderr(stdio());
+
+	let Some((deadline, timeout)) = ctx.deadline.get() else {
+		return Ok(cmd.output()?);
 	};
 
-	let mut cmd = std::process::Command::new(command);
-	cmd.args(extra_args.clone())
-		.arg("--noconfirm")
-		.arg("--needed")
-		.args(packages);
+	// In its own process group so anything it starts is terminated with it
+	let child = cmd.process_group(0).spawn()?;
+	let pgid = child.id();
+	let (sender, receiver) = mpsc::channel();
+	std::thread::spawn(move || sender.send(child.wait_with_output()));
+
+	if let Ok(output) = receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+		return Ok(output?);
+	}
+
+	signal_group(pgid, "TERM");
+	if receiver.recv_timeout(TIMEOUT_GRACE).is_err() {
+		signal_group(pgid, "KILL");
+	}
+	Err(Error::StepTimedOut(
+		owner.to_string_lossy().into_owned(),
+		timeout.as_secs(),
+	))
+}
 
-	if debug {
+fn run(ctx: &Context, owner: &Path, cmd: std::process::Command) -> Result<()> {
+	if ctx.debug {
 		debug!("{cmd:?}");
 	} else {
-		let cmd = cmd.output()?;
+		let cmd = wait_for(ctx, owner, cmd, true)?;
 		if !cmd.status.success() {
+			let stderr = String::from_utf8_lossy(&cmd.stderr);
 			return Err(Error::StepFailed(
 				owner.to_string_lossy().into_owned(),
-				String::from_utf8_lossy(&cmd.stderr).into_owned(),
+				if stderr.trim().is_empty() {
+					format!("Exited with {}", cmd.status)
+				} else {
+					stderr.into_owned()
+				},
 			));
 		}
 	}
@@ -218,18 +883,16 @@ fn install_packages(
 	Ok(())
 }
 
-fn run_command_raw<S: AsRef<OsStr>>(debug: bool, owner: &Path, command: &str, args: &[S], dir: &str) -> Result<()> {
-	let mut cmd = std::process::Command::new(command);
-	cmd.args(args).current_dir(dir);
-
-	if debug {
+/// Like `run`, but the command's output is shown as it runs instead of only when it fails.
+fn run_streaming(ctx: &Context, owner: &Path, cmd: std::process::Command) -> Result<()> {
+	if ctx.debug {
 		debug!("{cmd:?}");
 	} else {
-		let cmd = cmd.output()?;
-		if !cmd.status.success() {
+		let status = wait_for(ctx, owner, cmd, false)?.status;
+		if !status.success() {
 			return Err(Error::StepFailed(
 				owner.to_string_lossy().into_owned(),
-				String::from_utf8_lossy(&cmd.stderr).into_owned(),
+				format!("Exited with {status}"),
 			));
 		}
 	}
@@ -237,280 +900,1768 @@ fn run_command_raw<S: AsRef<OsStr>>(debug: bool, owner: &Path, command: &str, ar
 	Ok(())
 }
 
-fn run_command(debug: bool, owner: &Path, command: &str) -> Result<()> {
-	let command_and_args = command.split(' ').collect::<Vec<_>>();
-	let mut cmd = std::process::Command::new(command_and_args[0]);
-	cmd.args(&command_and_args[1..]);
+/// Works out what runs a script: the given interpreter, the script's shebang or `sh`, split into program and arguments.
+fn script_interpreter(path: &Path, interpreter: Option<&str>) -> Result<Vec<String>> {
+	let interpreter = if let Some(interpreter) = interpreter {
+		interpreter.to_string()
+	} else {
+		let mut first_line = String::new();
+		std::io::BufReader::new(std::fs::File::open(path)?).read_line(&mut first_line)?;
+		first_line.strip_prefix("#!").unwrap_or_default().to_string()
+	};
 
-	if debug {
-		debug!("{cmd:?}");
+	let interpreter = interpreter
+		.split_whitespace()
+		.map(ToString::to_string)
+		.collect::<Vec<_>>();
+	if interpreter.is_empty() {
+		Ok(vec!["sh".into()])
 	} else {
-		let cmd = cmd.output()?;
-		if !cmd.status.success() {
-			return Err(Error::StepFailed(
-				owner.to_string_lossy().into_owned(),
-				String::from_utf8_lossy(&cmd.stderr).into_owned(),
-			));
+		Ok(interpreter)
+	}
+}
+
+/// Repo packages are always installed as root, `run_as` is who runs the AUR helper.
+fn install_packages(
+	ctx: &Context,
+	owner: &Path,
+	run_as: &RunAs,
+	packages: &[String],
+	aur_flag: bool,
+	aur_helper: Option<AurHelper>,
+) -> Result<()> {
+	let mut cmd = if let Some(aur_helper) = aur_helper {
+		// AUR helpers and makepkg refuse to run as root and escalate by themselves
+		let run_as = if *run_as == RunAs::Root { &RunAs::User } else { run_as };
+		let mut cmd = ctx.privilege.command(run_as, aur_helper.binary());
+		match aur_helper {
+			AurHelper::Yay | AurHelper::Paru | AurHelper::Pikaur => {
+				cmd.arg(if aur_flag { "-Sa" } else { "-S" });
+			}
+			// aura only installs AUR packages with -A, anything else is passed on to pacman
+			AurHelper::Aura => {
+				cmd.arg(if aur_flag { "-A" } else { "-S" });
+			}
+			AurHelper::Trizen => {
+				cmd.arg("-S");
+				if aur_flag {
+					cmd.arg("--aur");
+				}
+			}
+			AurHelper::Makepkg => return aur::install_packages(ctx, owner, run_as, packages),
+		}
+		// Only yay and paru get here with doas or run0, the other helpers are rejected when the config is loaded
+		if let Escalation::Doas | Escalation::Run0 = ctx.privilege.escalation {
+			cmd.args(["--sudo", ctx.privilege.escalation.name()]);
 		}
+		if let Some(ref sources) = ctx.aur_sources {
+			cmd.env("SRCDEST", sources);
+		}
+		cmd
+	} else {
+		pacman(ctx, "-S")
+	};
+
+	cmd.arg("--noconfirm").arg("--needed").args(packages);
+	run(ctx, owner, cmd)
+}
+
+/// Builds a pacman command as root, using the package cache if one was given.
+fn pacman(ctx: &Context, operation: &str) -> std::process::Command {
+	let mut cmd = ctx.privilege.root_command("pacman");
+	cmd.arg(operation);
+	if let Some(ref cache) = ctx.package_cache {
+		cmd.arg("--cachedir").arg(cache);
 	}
+	cmd
+}
 
-	Ok(())
+fn run_command(
+	ctx: &Context,
+	owner: &Path,
+	run_as: &RunAs,
+	command: &str,
+	env: &BTreeMap<String, String>,
+	dir: Option<&Path>,
+) -> Result<()> {
+	let command_and_args = command.split(' ').collect::<Vec<_>>();
+	let mut cmd = ctx
+		.privilege
+		.command_env(run_as, command_and_args[0], env, &ctx.secret_env);
+	cmd.args(&command_and_args[1..]);
+	if let Some(dir) = dir {
+		cmd.current_dir(dir);
+	}
+
+	run(ctx, owner, cmd)
 }
 
-fn run_commands(debug: bool, owner: &Path, commands: &[String]) -> Result<()> {
+fn run_commands(
+	ctx: &Context,
+	owner: &Path,
+	run_as: &RunAs,
+	commands: &[String],
+	env: &BTreeMap<String, String>,
+	dir: Option<&Path>,
+) -> Result<()> {
 	for command in commands {
 		let chained_commands = command.split("&&");
 		for command in chained_commands {
-			run_command(debug, owner, command.trim())?;
+			run_command(ctx, owner, run_as, command.trim(), env, dir)?;
 		}
 	}
 
 	Ok(())
 }
 
-fn _main() -> Result<()> {
-	colog::default_builder().filter_level(log::LevelFilter::Debug).init();
+/// Builds the command that runs a script file with `interpreter`.
+fn script_command(
+	ctx: &Context,
+	run_as: &RunAs,
+	interpreter: &[String],
+	path: &Path,
+	args: &[String],
+	env: &BTreeMap<String, String>,
+	dir: &Path,
+) -> std::process::Command {
+	let mut cmd = ctx.privilege.command_env(run_as, &interpreter[0], env, &ctx.secret_env);
+	cmd.args(&interpreter[1..]).arg(path).args(args).current_dir(dir);
+	cmd
+}
 
-	check_os_release()?;
+/// Writes the body of an inline script to a temporary file only mrow can read, or only the user it runs as if that's
+/// another user.
+fn write_inline_script(ctx: &Context, owner: &Path, run_as: &RunAs, body: &str) -> Result<PathBuf> {
+	static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+	let path = std::env::temp_dir().join(format!(
+		"mrow-inline-{}-{}",
+		std::process::id(),
+		COUNTER.fetch_add(1, Ordering::Relaxed)
+	));
+	std::fs::OpenOptions::new()
+		.write(true)
+		.create_new(true)
+		.mode(0o600)
+		.open(&path)?
+		.write_all(body.as_bytes())?;
+
+	if let Some(user) = ctx.privilege.other_user(run_as) {
+		let mut chown = ctx.privilege.root_command("chown");
+		chown.arg("--").arg(user).arg(&path);
+		if let Err(err) = run(ctx, owner, chown) {
+			std::fs::remove_file(&path)?;
+			return Err(err);
+		}
+	}
 
-	let args = Args::parse();
-	let base_dir = match args.dir {
-		Some(ref dir) => PathBuf::from(dir).canonicalize()?,
-		None => std::env::current_dir()?,
-	};
+	Ok(path)
+}
 
-	if !base_dir.exists() {
-		error!("Dir '{}' doesn't exist!", base_dir.to_string_lossy());
-		exit(-1);
+/// Removes a script written by `write_inline_script`. Files given to another user are removed as root, since only
+/// their owner may remove them from the temporary directory.
+fn remove_inline_script(ctx: &Context, owner: &Path, run_as: &RunAs, path: &Path) -> Result<()> {
+	if ctx.privilege.other_user(run_as).is_none() {
+		return Ok(std::fs::remove_file(path)?);
 	}
 
-	let mut lua = true;
-	let mut root_file = base_dir.join("mrow.luau");
-	if !root_file.exists() {
-		root_file = base_dir.join("mrow.toml");
-		lua = false;
-		if !root_file.exists() {
-			error!("No mrow.toml or mrow.luau found in '{}'", base_dir.to_string_lossy());
-			exit(-1);
+	let mut rm = ctx.privilege.root_command("rm");
+	rm.args(["-f", "--"]).arg(path);
+	// Cleaning up isn't held to the step's timeout, which may have run out already
+	let deadline = ctx.deadline.take();
+	let result = run(ctx, owner, rm);
+	ctx.deadline.set(deadline);
+	result
+}
+
+/// Checks whether a guard skips its step. Returns the reason if it does.
+///
+/// Guard commands aren't run in dry-run mode, the step is assumed to run.
+fn check_guard(
+	ctx: &Context,
+	owner: &Path,
+	run_as: &RunAs,
+	guard: &Guard,
+	env: &BTreeMap<String, String>,
+	dir: Option<&Path>,
+) -> Result<Option<String>> {
+	if let Some(ref creates) = guard.creates {
+		if creates.exists() {
+			return Ok(Some(format!("'{}' exists", creates.to_string_lossy())));
 		}
 	}
 
-	let username = std::env::var("USER")?;
-
-	warn!("If the expected username is not '{username}' then CTRL-C and re-run!");
-	warn!(
-		"Adjust your sudo timestamp_timeout value to be longer than the install should take otherwise it may \
-		 eventually ask for authentication again."
-	);
-	warn!(
-		"To avoid this, CTRL+C and run `sudo visudo -f {username}`. Then paste the following line:Defaults \
-		 timestamp_timeout=<TIME_IN_MINUTES>"
-	);
-	println!();
-	info!(
-		"Enter your user password. The rest of the install wont require any user interaction unless it fails.Go make \
-		 tea!"
-	);
+	let succeeds = |command: &str| -> Result<Option<bool>> {
+		let command_and_args = command.split(' ').collect::<Vec<_>>();
+		let mut cmd = ctx
+			.privilege
+			.command_env(run_as, command_and_args[0], env, &ctx.secret_env);
+		cmd.args(&command_and_args[1..]);
+		if let Some(dir) = dir {
+			cmd.current_dir(dir);
+		}
 
-	let hostname = std::fs::read_to_string("/etc/hostname")?;
-	let hostname = hostname.trim();
-	let single_module = args.single_module.and_then(|p| p.canonicalize().ok());
-	let (all_steps, aur_helper) = if lua {
-		mrow_lua::process(base_dir, &root_file, single_module, hostname)?
-	} else {
-		mrow_toml::process(&base_dir, &root_file, single_module, hostname)?
+		if ctx.debug {
+			debug!("Guard: {cmd:?}");
+			return Ok(None);
+		}
+		Ok(Some(wait_for(ctx, owner, cmd, true)?.status.success()))
 	};
 
-	if !args.debug {
-		let sudo_out = std::process::Command::new("sudo").args(["ls"]).output()?;
-		if !sudo_out.status.success() {
-			error!("sudo elevation failed:");
-			error!("{}", String::from_utf8_lossy(sudo_out.stderr.as_slice()));
-			exit(-1);
+	if let Some(ref unless) = guard.unless {
+		if succeeds(unless)? == Some(true) {
+			return Ok(Some(format!("'{unless}' succeeded")));
+		}
+	}
+	if let Some(ref onlyif) = guard.onlyif {
+		if succeeds(onlyif)? == Some(false) {
+			return Ok(Some(format!("'{onlyif}' failed")));
 		}
 	}
 
-	println!();
-	if let Some(aur_helper) = aur_helper {
-		let name = match aur_helper {
-			AurHelper::Yay => "yay",
-			AurHelper::Paru => "paru-bin",
-		};
-
-		match run_command(args.debug, &root_file, &format!("pacman -Qi {name}")) {
-			Ok(()) => {
-				info!("AUR helper {name} is already installed, skipping install");
-			}
-			Err(Error::StepFailed(..)) => {
-				info!("AUR helper {name} not installed, installing now!");
-
-				info!("Installing prerequisite packages (base-devel group and git)");
-				install_packages(
-					args.debug,
-					&root_file,
-					&["base-devel".into(), "git".into()],
-					false,
-					None,
-				)?;
+	Ok(None)
+}
 
-				info!("Cloning {name} repo into /opt/{name}");
-				run_commands(
-					args.debug,
-					&root_file,
-					&[
-						format!("sudo git clone https://aur.archlinux.org/{name}.git /opt/{name}"),
-						format!("sudo chown -R {username}: /opt/{name}"),
-					],
-				)?;
+fn skip(relative_path_str: &str, reason: String) -> StepOutcome {
+	info!("[{relative_path_str}] Skipped, {reason}");
+	StepOutcome::Skipped(reason)
+}
 
-				info!("Building and installing {name}");
-				run_command_raw(
-					args.debug,
-					&root_file,
-					"makepkg",
-					&["-si", "--noconfirm"],
-					&format!("/opt/{name}"),
-				)?;
+/// Installs the packages that aren't installed yet, and marks the ones only installed as a dependency as explicitly
+/// installed.
+fn install_package_step(ctx: &Context, step: &Step, packages: &[String], aur: bool) -> Result<StepOutcome> {
+	let run_as = &step.options.run_as;
+	let as_suffix = run_as.suffix();
+	let missing = packages
+		.iter()
+		.filter(|package| !ctx.installed.is_installed(package))
+		.cloned()
+		.collect::<Vec<_>>();
+	let dependencies = packages
+		.iter()
+		.filter(|package| {
+			ctx.installed
+				.get(package)
+				.is_some_and(|package| package.reason == InstallReason::Dependency)
+		})
+		.cloned()
+		.collect::<Vec<_>>();
+
+	if missing.is_empty() && dependencies.is_empty() {
+		info!("[{}] Already installed: {}", step.relative_path_str, packages.join(" "));
+		return Ok(StepOutcome::Unchanged);
+	}
 
-				info!("{name} installed");
-			}
-			Err(err) => Err(err)?,
-		}
+	if let [package] = missing.as_slice() {
+		info!(
+			"[{}] Installing {}package: {package}{as_suffix}",
+			step.relative_path_str,
+			if aur { "AUR " } else { "" },
+		);
+	} else if !missing.is_empty() {
+		info!(
+			"[{}] Installing {}packages{as_suffix}:\n{}",
+			step.relative_path_str,
+			if aur { "AUR " } else { "" },
+			missing.join("\n")
+		);
+	}
+	if !missing.is_empty() {
+		refresh_repos(ctx, &step.owner)?;
+		install_packages(ctx, &step.owner, run_as, &missing, aur, ctx.aur_helper.filter(|_| aur))?;
 	}
 
-	if aur_helper.is_none() {
-		for step in &all_steps {
-			if let StepKind::InstallPackage { package: _, aur: true }
-			| StepKind::InstallPackages { packages: _, aur: true } = step.kind
-			{
-				error!(
-					"An install package step in '{}' requires AUR but there is no AUR helper set in your mrow.toml",
-					step.relative_path_str
-				);
-				exit(-1);
-			}
-		}
+	if !dependencies.is_empty() {
+		info!(
+			"[{}] Marking as explicitly installed: {}",
+			step.relative_path_str,
+			dependencies.join(" ")
+		);
+		let mut cmd = ctx.privilege.root_command("pacman");
+		cmd.args(["-D", "--asexplicit"]).args(&dependencies);
+		run(ctx, &step.owner, cmd)?;
 	}
 
-	for step in all_steps {
-		match step.kind {
-			StepKind::InstallPackage { package, aur } => {
-				info!(
-					"[{}] Installing {}package: {}",
-					step.relative_path_str,
-					if aur { "AUR " } else { "" },
-					package
-				);
+	Ok(StepOutcome::Ran)
+}
+
+/// Removes the packages that are installed, absent packages are left alone.
+fn remove_package_step(
+	ctx: &Context,
+	step: &Step,
+	packages: &[String],
+	dependencies: bool,
+	config_files: bool,
+) -> Result<StepOutcome> {
+	let installed = packages
+		.iter()
+		.filter(|package| ctx.installed.get(package).is_some())
+		.cloned()
+		.collect::<Vec<_>>();
+	if installed.is_empty() {
+		info!("[{}] Not installed: {}", step.relative_path_str, packages.join(" "));
+		return Ok(StepOutcome::Unchanged);
+	}
+
+	info!(
+		"[{}] Removing package(s): {}",
+		step.relative_path_str,
+		installed.join(" ")
+	);
+	let mut flags = String::from("-R");
+	if dependencies {
+		flags.push('s');
+	}
+	if config_files {
+		flags.push('n');
+	}
+	let mut cmd = ctx.privilege.root_command("pacman");
+	cmd.arg(flags).arg("--noconfirm").args(&installed);
+	run(ctx, &step.owner, cmd)?;
+	Ok(StepOutcome::Ran)
+}
+
+/// Builds a local PKGBUILD and installs the packages it builds, unless they're installed at the version it builds.
+fn local_package_step(ctx: &Context, step: &Step, dir: &Path) -> Result<StepOutcome> {
+	// makepkg refuses to run as root and installs dependencies by itself
+	let run_as = if step.options.run_as == RunAs::Root {
+		&RunAs::User
+	} else {
+		&step.options.run_as
+	};
+	let dir_str = dir.to_string_lossy();
+
+	// Only reads the PKGBUILD, so this also runs with --debug
+	let srcinfo = makepkg_output(ctx, step, run_as, dir, &BTreeMap::new(), "--printsrcinfo")?;
+	let names = aur::package_names(&srcinfo);
+	let version = aur::version(&srcinfo);
+	ctx.local_packages.borrow_mut().extend(names.iter().cloned());
+
+	let installed = version.as_ref().is_some_and(|version| {
+		!names.is_empty()
+			&& names.iter().all(|name| {
+				ctx.installed
+					.get(name)
+					.is_some_and(|package| package.version == *version)
+			})
+	});
+	if installed {
+		info!(
+			"[{}] Already installed: {} {}",
+			step.relative_path_str,
+			names.join(" "),
+			version.unwrap_or_default()
+		);
+		return Ok(StepOutcome::Unchanged);
+	}
+
+	info!(
+		"[{}] Building local package '{dir_str}'{}",
+		step.relative_path_str,
+		run_as.suffix()
+	);
+	refresh_repos(ctx, &step.owner)?;
+
+	// Built outside of the PKGBUILD's directory, which is likely in a git repo
+	let cache = privilege::home_dir()
+		.unwrap_or_else(std::env::temp_dir)
+		.join(".cache")
+		.join("mrow")
+		.join("local")
+		.join(aur::package_base(&srcinfo).unwrap_or_else(|| names.first().map_or("unknown", String::as_str)));
+	let mut env = BTreeMap::from([
+		(
+			"BUILDDIR".to_string(),
+			cache.join("build").to_string_lossy().into_owned(),
+		),
+		(
+			"PKGDEST".to_string(),
+			cache.join("packages").to_string_lossy().into_owned(),
+		),
+		(
+			"SRCDEST".to_string(),
+			ctx.aur_sources
+				.clone()
+				.unwrap_or_else(|| cache.join("sources"))
+				.to_string_lossy()
+				.into_owned(),
+		),
+	]);
+	let mut mkdir = ctx.privilege.command(run_as, "mkdir");
+	mkdir.arg("-p").args(env.values());
+	run(ctx, &step.owner, mkdir)?;
+	if let Escalation::Doas | Escalation::Run0 = ctx.privilege.escalation {
+		// Used by makepkg to install dependencies
+		env.insert("PACMAN_AUTH".into(), ctx.privilege.escalation.name().into());
+	}
+
+	let mut makepkg = ctx.privilege.command_env(run_as, "makepkg", &env, &ctx.secret_env);
+	makepkg.args(["-sf", "--noconfirm"]).current_dir(dir);
+	run(ctx, &step.owner, makepkg)?;
+
+	// Only the packages of the PKGBUILD, not the -debug packages makepkg may split off
+	let built = makepkg_output(ctx, step, run_as, dir, &env, "--packagelist")?;
+	let version = version.unwrap_or_default();
+	let packages = built
+		.lines()
+		.filter(|line| {
+			Path::new(line).file_name().is_some_and(|file| {
+				let file = file.to_string_lossy();
+				names.iter().any(|name| file.starts_with(&format!("{name}-{version}-")))
+			})
+		})
+		.collect::<Vec<_>>();
+	info!("[{}] Installing {}", step.relative_path_str, names.join(" "));
+	let mut pacman = pacman(ctx, "-U");
+	pacman.arg("--noconfirm").args(packages);
+	run(ctx, &step.owner, pacman)?;
+	Ok(StepOutcome::Ran)
+}
+
+/// Runs a makepkg query in `dir` and returns what it printed.
+fn makepkg_output(
+	ctx: &Context,
+	step: &Step,
+	run_as: &RunAs,
+	dir: &Path,
+	env: &BTreeMap<String, String>,
+	query: &str,
+) -> Result<String> {
+	let output = ctx
+		.privilege
+		.command_env(run_as, "makepkg", env, &ctx.secret_env)
+		.arg(query)
+		.current_dir(dir)
+		.output()?;
+	if !output.status.success() {
+		return Err(Error::StepFailed(
+			step.owner.to_string_lossy().into_owned(),
+			format!(
+				"makepkg {query} failed. {}",
+				String::from_utf8_lossy(&output.stderr).trim()
+			),
+		));
+	}
+	Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Refreshes the package databases once after pacman repo steps changed pacman.conf, as the sync policy says.
+fn refresh_repos(ctx: &Context, owner: &Path) -> Result<()> {
+	if !ctx.repos_changed.get() {
+		return Ok(());
+	}
+	let flag = match ctx.sync_policy {
+		// Upgrading again, so packages from the changed repos aren't installed on top of older databases
+		SyncPolicy::Upgrade => {
+			info!("Upgrading the system with the changed repos");
+			"-Syu"
+		}
+		SyncPolicy::Sync => {
+			info!("Refreshing the package databases for the changed repos");
+			"-Sy"
+		}
+		SyncPolicy::Never => {
+			warn!(
+				"The package databases aren't refreshed with sync policy 'never', packages from the changed repos \
+				 can't be installed"
+			);
+			ctx.repos_changed.set(false);
+			return Ok(());
+		}
+	};
+	let mut cmd = pacman(ctx, flag);
+	cmd.arg("--noconfirm");
+	run(ctx, owner, cmd)?;
+	ctx.repos_changed.set(false);
+	Ok(())
+}
 
-				install_packages(args.debug, &step.owner, &[package], aur, aur_helper.filter(|_| aur))?;
+/// Imports and locally signs the repo's key unless it's in pacman's keyring, and writes the repo's section to
+/// pacman.conf if it differs.
+fn pacman_repo_step(ctx: &Context, step: &Step) -> Result<StepOutcome> {
+	let StepKind::PacmanRepo {
+		ref name,
+		ref servers,
+		ref include,
+		ref sig_level,
+		ref key,
+		ref keyserver,
+	} = step.kind
+	else {
+		unreachable!("only called for pacman repo steps")
+	};
+	let mut changed = false;
+
+	if let Some(key) = key {
+		let known = ctx
+			.privilege
+			.root_command("pacman-key")
+			.args(["--list-keys", key])
+			.stdout(Stdio::null())
+			.stderr(Stdio::null())
+			.status()
+			.is_ok_and(|status| status.success());
+		if !known {
+			info!("[{}] Importing and signing key {key}", step.relative_path_str);
+			let mut recv = ctx.privilege.root_command("pacman-key");
+			recv.args(["--recv-keys", key]);
+			if let Some(keyserver) = keyserver {
+				recv.args(["--keyserver", keyserver]);
 			}
-			StepKind::InstallPackages { packages, aur } => {
-				info!(
-					"[{}] Installing {}packages:\n{}",
-					step.relative_path_str,
-					if aur { "AUR " } else { "" },
-					packages.join("\n")
-				);
+			run(ctx, &step.owner, recv)?;
+
+			let mut lsign = ctx.privilege.root_command("pacman-key");
+			lsign.args(["--lsign-key", key]);
+			run(ctx, &step.owner, lsign)?;
+			changed = true;
+		}
+	}
+
+	let mut section = format!("[{name}]\n");
+	if let Some(sig_level) = sig_level {
+		section.push_str(&format!("SigLevel = {sig_level}\n"));
+	}
+	for server in servers {
+		section.push_str(&format!("Server = {server}\n"));
+	}
+	if let Some(include) = include {
+		section.push_str(&format!("Include = {include}\n"));
+	}
 
-				install_packages(args.debug, &step.owner, &packages, aur, aur_helper.filter(|_| aur))?;
+	let conf = std::fs::read_to_string(pacman::PACMAN_CONF)?;
+	let updated = pacman::set_repo(&conf, name, &section);
+	if updated != conf {
+		info!(
+			"[{}] Writing repo [{name}] to {}",
+			step.relative_path_str,
+			pacman::PACMAN_CONF
+		);
+		let mut write = ctx.privilege.root_command("tee");
+		write.arg(pacman::PACMAN_CONF);
+		if ctx.debug {
+			debug!("{write:?}");
+		} else {
+			let output = secrets::run_with_stdin(write.stdout(Stdio::null()), updated.as_bytes())?;
+			if !output.status.success() {
+				return Err(Error::StepFailed(
+					step.owner.to_string_lossy().into_owned(),
+					String::from_utf8_lossy(&output.stderr).into_owned(),
+				));
 			}
-			StepKind::CopyFile { from, to, as_root } => {
-				info!(
-					"[{}] Copying file '{}' to '{}'{}",
-					step.relative_path_str,
-					from.to_string_lossy(),
-					to.to_string_lossy(),
-					if as_root { " as root" } else { "" }
+		}
+		ctx.repos_changed.set(true);
+		changed = true;
+	}
+
+	if changed {
+		Ok(StepOutcome::Ran)
+	} else {
+		info!("[{}] Repo [{name}] is already configured", step.relative_path_str);
+		Ok(StepOutcome::Unchanged)
+	}
+}
+
+fn run_step(ctx: &Context, step: Step) -> Result<StepOutcome> {
+	let run_as = &step.options.run_as;
+	let as_suffix = run_as.suffix();
+	let mut step_env = ctx.env.clone();
+	step_env.insert("MROW_MODULE".into(), step.relative_path_str.clone());
+
+	match step.kind {
+		StepKind::InstallPackage { ref package, aur } => {
+			return install_package_step(ctx, &step, std::slice::from_ref(package), aur);
+		}
+		StepKind::InstallPackages { ref packages, aur } => {
+			return install_package_step(ctx, &step, packages, aur);
+		}
+		StepKind::RemovePackage {
+			ref package,
+			dependencies,
+			config_files,
+		} => {
+			return remove_package_step(ctx, &step, std::slice::from_ref(package), dependencies, config_files);
+		}
+		StepKind::RemovePackages {
+			ref packages,
+			dependencies,
+			config_files,
+		} => {
+			return remove_package_step(ctx, &step, packages, dependencies, config_files);
+		}
+		StepKind::LocalPackage { ref path } => {
+			return local_package_step(ctx, &step, path);
+		}
+		StepKind::PacmanRepo { .. } => {
+			return pacman_repo_step(ctx, &step);
+		}
+		StepKind::CopyFile { from, to } => {
+			info!(
+				"[{}] Copying file '{}' to '{}'{as_suffix}",
+				step.relative_path_str,
+				from.to_string_lossy(),
+				to.to_string_lossy(),
+			);
+
+			// An unreadable destination, e.g. one only root can read, is copied anyway
+			if std::fs::read(&to).is_ok_and(|existing| std::fs::read(&from).is_ok_and(|new| new == existing)) {
+				info!("[{}] '{}' is up to date", step.relative_path_str, to.to_string_lossy());
+				return Ok(StepOutcome::Unchanged);
+			}
+
+			let mut cmd = ctx.privilege.command(run_as, "cp");
+			cmd.arg(&from).arg(&to);
+			run(ctx, &step.owner, cmd)?;
+		}
+		StepKind::SecretFile { from, to } => {
+			info!(
+				"[{}] Decrypting secret '{}' to '{}'{as_suffix}",
+				step.relative_path_str,
+				from.to_string_lossy(),
+				to.to_string_lossy(),
+			);
+
+			if !secrets::install_file(ctx, &step.owner, run_as, &from, &to)? {
+				info!("[{}] '{}' is up to date", step.relative_path_str, to.to_string_lossy());
+				return Ok(StepOutcome::Unchanged);
+			}
+		}
+		StepKind::Symlink {
+			from,
+			to,
+			delete_existing,
+		} => {
+			info!(
+				"[{}] Creating symlink from '{}' to '{}'{}{as_suffix}",
+				step.relative_path_str,
+				from.to_string_lossy(),
+				to.to_string_lossy(),
+				if delete_existing {
+					" deleting anything in its current place"
+				} else {
+					""
+				}
+			);
+
+			if std::fs::read_link(&to).is_ok_and(|target| target == from) {
+				info!("[{}] Symlink is already in place", step.relative_path_str);
+				return Ok(StepOutcome::Unchanged);
+			}
+
+			// symlink_metadata so broken symlinks count as existing too
+			let exists = to.symlink_metadata().is_ok();
+			if exists && !delete_existing {
+				warn!("Not creating symlink as the destination already exists");
+				return Ok(StepOutcome::Skipped("the destination already exists".into()));
+			}
+
+			// Paths are passed as single arguments, so spaces in them are kept
+			if exists {
+				let mut rm = ctx.privilege.command(run_as, "rm");
+				rm.arg("-rf").arg("--").arg(&to);
+				run(ctx, &step.owner, rm)?;
+			} else if let Some(to_parent) = to.parent().filter(|parent| !parent.exists()) {
+				let mut mkdir = ctx.privilege.command(run_as, "mkdir");
+				mkdir.arg("-p").arg("--").arg(to_parent);
+				run(ctx, &step.owner, mkdir)?;
+			}
+
+			let mut ln = ctx.privilege.command(run_as, "ln");
+			ln.arg("-s").arg("--").arg(&from).arg(&to);
+			run(ctx, &step.owner, ln)?;
+		}
+		StepKind::RunCommand {
+			command,
+			env,
+			cwd,
+			guard,
+		} => {
+			info!("[{}] Running command '{}'{as_suffix}", step.relative_path_str, &command);
+
+			step_env.extend(env);
+			if let Some(reason) = check_guard(ctx, &step.owner, run_as, &guard, &step_env, cwd.as_deref())? {
+				return Ok(skip(&step.relative_path_str, reason));
+			}
+			run_commands(ctx, &step.owner, run_as, &[command], &step_env, cwd.as_deref())?;
+		}
+		StepKind::RunCommands {
+			commands,
+			env,
+			cwd,
+			guard,
+		} => {
+			info!(
+				"[{}] Running commands{as_suffix}:\n{}",
+				step.relative_path_str,
+				commands.join("\n")
+			);
+
+			step_env.extend(env);
+			if let Some(reason) = check_guard(ctx, &step.owner, run_as, &guard, &step_env, cwd.as_deref())? {
+				return Ok(skip(&step.relative_path_str, reason));
+			}
+			run_commands(ctx, &step.owner, run_as, &commands, &step_env, cwd.as_deref())?;
+		}
+		StepKind::RunScript {
+			path,
+			interpreter,
+			args,
+			env,
+			cwd,
+			guard,
+		} => {
+			info!(
+				"[{}] Running script '{}'{as_suffix}",
+				step.relative_path_str,
+				path.to_string_lossy()
+			);
+
+			step_env.extend(env);
+			let interpreter = script_interpreter(&path, interpreter.as_deref())?;
+			let dir = cwd.unwrap_or_else(|| {
+				path.parent()
+					.unwrap_or_else(|| {
+						unreachable!(
+							"the program doesn't allow for placing a mrow.toml file in the root of a filesystem"
+						)
+					})
+					.to_path_buf()
+			});
+			if let Some(reason) = check_guard(ctx, &step.owner, run_as, &guard, &step_env, Some(&dir))? {
+				return Ok(skip(&step.relative_path_str, reason));
+			}
+			let cmd = script_command(ctx, run_as, &interpreter, &path, &args, &step_env, &dir);
+			run_streaming(ctx, &step.owner, cmd)?;
+		}
+		StepKind::InlineScript {
+			body,
+			interpreter,
+			env,
+			cwd,
+			guard,
+		} => {
+			info!(
+				"[{}] Running inline script{as_suffix}:\n{}",
+				step.relative_path_str,
+				body.trim_end()
+			);
+
+			step_env.extend(env);
+			let interpreter = script_interpreter(Path::new(""), Some(interpreter.as_deref().unwrap_or("sh")))?;
+			let dir = cwd.unwrap_or_else(|| {
+				step.owner
+					.parent()
+					.unwrap_or_else(|| {
+						unreachable!(
+							"the program doesn't allow for placing a mrow.toml file in the root of a filesystem"
+						)
+					})
+					.to_path_buf()
+			});
+
+			if let Some(reason) = check_guard(ctx, &step.owner, run_as, &guard, &step_env, Some(&dir))? {
+				return Ok(skip(&step.relative_path_str, reason));
+			}
+			if ctx.debug {
+				let cmd = script_command(
+					ctx,
+					run_as,
+					&interpreter,
+					Path::new("<inline script>"),
+					&[],
+					&step_env,
+					&dir,
 				);
+				run_streaming(ctx, &step.owner, cmd)?;
+			} else {
+				let path = write_inline_script(ctx, &step.owner, run_as, &body)?;
+				let cmd = script_command(ctx, run_as, &interpreter, &path, &[], &step_env, &dir);
+				let result = run_streaming(ctx, &step.owner, cmd);
+				let removed = remove_inline_script(ctx, &step.owner, run_as, &path);
+				// A failing script is the more useful error
+				result?;
+				removed?;
+			}
+		}
+	}
 
-				run_commands(
-					args.debug,
-					&step.owner,
-					&[format!(
-						"{}cp {} {}",
-						if as_root { "sudo " } else { "" },
-						from.to_string_lossy(),
-						to.to_string_lossy()
-					)],
-				)?;
-			}
-			StepKind::Symlink {
-				from,
-				to,
-				delete_existing,
-			} => {
-				info!(
-					"[{}] Creating symlink from '{}' to '{}'{}",
-					step.relative_path_str,
-					from.to_string_lossy(),
-					to.to_string_lossy(),
-					if delete_existing {
-						" deleting anything in its current place"
-					} else {
-						""
-					}
+	Ok(StepOutcome::Ran)
+}
+
+/// Makes sure every package that will be installed exists, before anything is changed. Only packages that aren't
+/// installed yet are checked, so a fully applied config doesn't have to read the package lists.
+fn check_packages(plan: &Plan, installed: &LocalDb) -> Result<()> {
+	let missing = plan
+		.all_steps()
+		.filter_map(|step| step_packages(step).map(|(packages, aur)| (step, packages, aur)))
+		.flat_map(|(step, packages, aur)| {
+			packages
+				.iter()
+				.filter(|package| !installed.is_installed(package))
+				.map(move |package| (step, package, aur))
+		})
+		.collect::<Vec<_>>();
+	if missing.is_empty() {
+		return Ok(());
+	}
+
+	let sync = match SyncDb::load() {
+		Ok(sync) if !sync.is_empty() => sync,
+		Ok(_) => {
+			warn!("The package databases are empty, not checking package names");
+			return Ok(());
+		}
+		Err(err) => {
+			warn!("Couldn't read the package databases, not checking package names: {err}");
+			return Ok(());
+		}
+	};
+	// Only downloaded if a package isn't in the repos or is from the AUR
+	let aur = OnceCell::new();
+	let aur = || {
+		aur.get_or_init(|| {
+			pacman::aur_packages()
+				.inspect_err(|err| warn!("Couldn't get the list of AUR packages, not checking AUR packages: {err}"))
+				.ok()
+		})
+		.as_ref()
+	};
+	let did_you_mean = |suggestions: Vec<&str>| {
+		if suggestions.is_empty() {
+			String::new()
+		} else {
+			format!(". Did you mean '{}'?", suggestions.join("', '"))
+		}
+	};
+
+	// Packages may come from repos that pacman repo steps add, which can't be checked before they're synced
+	let pending = plan
+		.all_steps()
+		.filter_map(|step| match &step.kind {
+			StepKind::PacmanRepo { name, .. } if !pacman::has_sync_db(name) => Some(name.as_str()),
+			_ => None,
+		})
+		.collect::<Vec<_>>();
+
+	let mut unknown = 0;
+	for (step, package, is_aur) in missing {
+		let module = &step.relative_path_str;
+		if is_aur {
+			match aur() {
+				Some(aur) if aur.contains(package) => {}
+				_ if sync.contains(package) => {
+					warn!("[{module}] '{package}' is in the repos, it doesn't have to be installed from the AUR");
+				}
+				Some(aur) => {
+					error!(
+						"[{module}] AUR package '{package}' doesn't exist{}",
+						did_you_mean(pacman::suggestions(package, aur))
+					);
+					unknown += 1;
+				}
+				None => {}
+			}
+		} else if !sync.contains(package) && !pending.is_empty() {
+			warn!(
+				"[{module}] '{package}' isn't in the synced repos, it may be in a repo that isn't synced yet: '{}'",
+				pending.join("', '")
+			);
+		} else if !sync.contains(package) {
+			if aur().is_some_and(|aur| aur.contains(package)) {
+				error!("[{module}] '{package}' is an AUR package, the step needs 'aur' set");
+			} else {
+				error!(
+					"[{module}] Package '{package}' doesn't exist{}",
+					did_you_mean(pacman::suggestions(package, sync.packages()))
 				);
+			}
+			unknown += 1;
+		}
+	}
+
+	if unknown > 0 {
+		return Err(Error::UnknownPackages(unknown));
+	}
+	Ok(())
+}
+
+/// After confirmation marks or removes the explicitly installed packages that aren't listed, as configured by
+/// `unlisted`. A package counts as listed if its name, a name it provides or one of its groups is.
+fn prune_packages(
+	ctx: &Context,
+	root_file: &Path,
+	unlisted: UnlistedPackages,
+	listed: &BTreeSet<String>,
+) -> Result<()> {
+	// Read again since the steps may have removed packages, which pacman would fail to find
+	let installed = LocalDb::load();
+	let extras = installed
+		.as_ref()
+		.unwrap_or(&ctx.installed)
+		.iter()
+		.filter(|(name, package)| {
+			package.reason == InstallReason::Explicit
+				&& !listed.contains(*name)
+				&& !package
+					.provides
+					.iter()
+					.chain(&package.groups)
+					.any(|name| listed.contains(name))
+		})
+		.collect::<Vec<_>>();
+	if extras.is_empty() {
+		info!("Every explicitly installed package is listed");
+		return Ok(());
+	}
+
+	warn!(
+		"{} explicitly installed package(s) aren't listed in any package step:",
+		extras.len()
+	);
+	for (name, package) in &extras {
+		warn!("  - {name} {}", package.version);
+	}
+
+	let (question, args) = match unlisted {
+		UnlistedPackages::Keep => return Ok(()),
+		UnlistedPackages::Mark => ("Mark them as installed as a dependency?", ["-D", "--asdeps"]),
+		UnlistedPackages::Remove => ("Remove them?", ["-Rs", "--noconfirm"]),
+	};
+	// Nothing is changed in debug mode, so there's nothing to confirm
+	if !ctx.debug && !prompts::confirm(question)? {
+		info!("Leaving them installed");
+		return Ok(());
+	}
+
+	let mut cmd = ctx.privilege.root_command("pacman");
+	cmd.args(args).args(extras.iter().map(|(name, _)| name));
+	run(ctx, root_file, cmd)
+}
+
+/// Every package of package steps and whether it's from the AUR.
+fn plan_packages(plan: &Plan) -> Vec<(&String, bool)> {
+	plan.all_steps()
+		.filter_map(step_packages)
+		.flat_map(|(packages, aur)| packages.iter().map(move |package| (package, aur)))
+		.collect()
+}
+
+/// Writes the installed versions of every package in the config to `mrow.lock`.
+fn lock_packages(plan: &Plan, base_dir: &Path) -> Result<()> {
+	let installed = LocalDb::load()?;
+	let mut lock = Lockfile::default();
+	for (package, aur) in plan_packages(plan) {
+		let Some(local) = installed.get(package) else {
+			warn!("'{package}' isn't installed, or is a group or provided name, not locking it");
+			continue;
+		};
+
+		if aur {
+			let commit = if let Some(commit) = aur::built_commit(package, &local.version) {
+				commit
+			} else {
+				warn!("'{package}' wasn't built from a repo mrow cloned, locking the commit its AUR repo is at now");
+				lock::aur_commit(package)?
+			};
+			lock.aur.insert(
+				package.clone(),
+				AurPin {
+					version: local.version.clone(),
+					commit,
+				},
+			);
+		} else {
+			lock.packages.insert(package.clone(), local.version.clone());
+		}
+	}
 
-				if to.exists() && !delete_existing {
-					warn!("Not creating symlink as the destination already exists");
-					continue;
+	lock.save(base_dir)?;
+	info!(
+		"Locked {} package(s) to '{}'",
+		lock.packages.len() + lock.aur.len(),
+		Lockfile::path(base_dir).to_string_lossy()
+	);
+	Ok(())
+}
+
+/// Compares the packages with `mrow.lock` before anything is changed, as set by `policy`.
+fn check_lock(
+	plan: &Plan,
+	installed: &LocalDb,
+	base_dir: &Path,
+	policy: LockPolicy,
+	aur_helper: Option<AurHelper>,
+) -> Result<()> {
+	if policy == LockPolicy::Ignore {
+		return Ok(());
+	}
+	let Some(lock) = Lockfile::load(base_dir)? else {
+		warn!("There's no mrow.lock to compare packages with, create one with 'mrow lock'");
+		return Ok(());
+	};
+
+	let packages = plan_packages(plan);
+	// Only needed to know what version packages that aren't installed yet would get
+	let sync = packages
+		.iter()
+		.any(|(package, _)| !installed.is_installed(package))
+		.then(SyncDb::load)
+		.and_then(Result::ok);
+	// Only the makepkg backend builds the locked commit
+	let aur_pinned = aur_helper == Some(AurHelper::Makepkg);
+	let deviations = lock.deviations(&packages, installed, sync.as_ref(), aur_pinned);
+	if deviations.is_empty() {
+		return Ok(());
+	}
+
+	for deviation in &deviations {
+		if policy == LockPolicy::Fail {
+			error!("{deviation}");
+		} else {
+			warn!("{deviation}");
+		}
+	}
+	if policy == LockPolicy::Fail {
+		return Err(Error::LockDeviations(deviations.len()));
+	}
+	Ok(())
+}
+
+/// Refreshes the package databases once for the whole run.
+///
+/// With `download` the upgrade and the missing packages are downloaded in between refreshing the databases and
+/// upgrading, so nothing is changed until everything was downloaded.
+fn sync_packages(
+	ctx: &Context,
+	root_file: &Path,
+	policy: SyncPolicy,
+	download: Option<&[(String, bool)]>,
+) -> Result<()> {
+	let Some(missing) = download else {
+		let flag = match policy {
+			SyncPolicy::Upgrade => {
+				info!("Upgrading the system");
+				"-Syu"
+			}
+			SyncPolicy::Sync => {
+				info!("Syncing package databases");
+				"-Sy"
+			}
+			SyncPolicy::Never => return Ok(()),
+		};
+
+		let mut cmd = pacman(ctx, flag);
+		cmd.arg("--noconfirm");
+		return run(ctx, root_file, cmd);
+	};
+
+	if policy != SyncPolicy::Never {
+		info!("Syncing package databases");
+		let mut cmd = pacman(ctx, "-Sy");
+		cmd.arg("--noconfirm");
+		run(ctx, root_file, cmd)?;
+	}
+
+	let upgrade = policy == SyncPolicy::Upgrade;
+	let repo = missing
+		.iter()
+		.filter(|(_, aur)| !aur)
+		.map(|(package, _)| package)
+		.collect::<Vec<_>>();
+	if upgrade || !repo.is_empty() {
+		info!("Downloading packages");
+		let mut cmd = pacman(ctx, if upgrade { "-Swu" } else { "-Sw" });
+		cmd.arg("--noconfirm").args(repo);
+		run(ctx, root_file, cmd)?;
+	}
+
+	let aur = missing
+		.iter()
+		.filter(|(_, aur)| *aur)
+		.map(|(package, _)| package.clone())
+		.collect::<Vec<_>>();
+	let mut upgraded = false;
+	if !aur.is_empty() && ctx.aur_sources.is_some() {
+		if !ctx.installed.is_installed("git") {
+			// Installed from what was just downloaded, so nothing is changed unless the repo packages are all there
+			info!("Installing git to download AUR packages with");
+			let mut cmd = pacman(ctx, if upgrade { "-Su" } else { "-S" });
+			cmd.args(["--noconfirm", "--needed", "git"]);
+			run(ctx, root_file, cmd)?;
+			upgraded = upgrade;
+		}
+
+		let dependencies = aur::download_packages(ctx, root_file, &aur)?;
+		if !dependencies.is_empty() {
+			info!("Downloading the dependencies of AUR packages");
+			let mut cmd = pacman(ctx, "-Sw");
+			cmd.arg("--noconfirm").args(dependencies);
+			run(ctx, root_file, cmd)?;
+		}
+	}
+
+	if upgrade && !upgraded {
+		info!("Upgrading the system");
+		let mut cmd = pacman(ctx, "-Su");
+		cmd.arg("--noconfirm");
+		run(ctx, root_file, cmd)?;
+	}
+	Ok(())
+}
+
+/// Whether base-devel and git, which building AUR packages needs, are installed.
+fn has_aur_prerequisites(installed: &LocalDb) -> bool {
+	["base-devel", "git"]
+		.iter()
+		.all(|package| installed.is_installed(package))
+}
+
+/// Installs the AUR helper from the AUR if it isn't installed yet, and the packages needed to build AUR packages.
+fn bootstrap_aur_helper(
+	ctx: &Context,
+	root_file: &Path,
+	aur_helper: AurHelper,
+	bootstrap: &AurBootstrap,
+	username: &str,
+) -> Result<()> {
+	let missing = bootstrap.missing(aur_helper, &ctx.installed);
+	let prerequisites = has_aur_prerequisites(&ctx.installed);
+
+	match missing {
+		Some(name) => info!("AUR helper {name} not installed, installing now!"),
+		None if aur_helper != AurHelper::Makepkg => {
+			info!(
+				"AUR helper {} is already installed, skipping install",
+				aur_helper.binary()
+			);
+		}
+		None => {}
+	}
+
+	if !prerequisites {
+		info!("Installing prerequisite packages (base-devel group and git)");
+		install_packages(
+			ctx,
+			root_file,
+			&RunAs::User,
+			&["base-devel".into(), "git".into()],
+			false,
+			None,
+		)?;
+	}
+
+	let Some(name) = missing else {
+		return Ok(());
+	};
+	let dir = bootstrap.build_dir.as_deref().unwrap_or(Path::new("/opt")).join(name);
+
+	// Left over from an earlier bootstrap that wasn't cleaned up
+	if dir.exists() {
+		remove_build_dir(ctx, root_file, &dir)?;
+	}
+
+	let result = build_aur_helper(ctx, root_file, bootstrap, name, &dir, username);
+
+	let cleanup = match bootstrap.cleanup {
+		BootstrapCleanup::Never => false,
+		BootstrapCleanup::OnSuccess => result.is_ok(),
+		BootstrapCleanup::Always => true,
+	};
+	let removed = if cleanup {
+		remove_build_dir(ctx, root_file, &dir)
+	} else {
+		Ok(())
+	};
+	result?;
+	removed?;
+
+	info!("{name} installed");
+
+	Ok(())
+}
+
+/// Clones the AUR helper's repo into `dir`, checks it as configured and builds and installs it.
+fn build_aur_helper(
+	ctx: &Context,
+	root_file: &Path,
+	bootstrap: &AurBootstrap,
+	name: &str,
+	dir: &Path,
+	username: &str,
+) -> Result<()> {
+	let dir_str = dir.to_string_lossy();
+	let fetched = aur::repos_dir().join(name);
+	if ctx.aur_sources.is_some() && fetched.exists() {
+		// Fetched by --download-first, git refuses to clone repos owned by someone else so the user clones it
+		info!("Cloning {name} repo into {dir_str} from {}", fetched.to_string_lossy());
+		let mut mkdir = ctx.privilege.root_command("install");
+		mkdir.args(["-d", "-o", username, "--"]).arg(dir);
+		run(ctx, root_file, mkdir)?;
+
+		let mut clone = ctx.privilege.user_command("git");
+		clone.arg("clone").arg(&fetched).arg(dir);
+		run(ctx, root_file, clone)?;
+	} else {
+		info!("Cloning {name} repo into {dir_str}");
+		let mut clone = ctx.privilege.root_command("git");
+		clone
+			.arg("clone")
+			.arg(format!("https://aur.archlinux.org/{name}.git"))
+			.arg(dir);
+		run(ctx, root_file, clone)?;
+
+		let mut chown = ctx.privilege.root_command("chown");
+		chown.arg("-R").arg(format!("{username}:")).arg(dir);
+		run(ctx, root_file, chown)?;
+	}
+
+	// The clone only happened for real outside of --debug
+	if !ctx.debug {
+		if let Some(ref pinned) = bootstrap.commit {
+			check_bootstrap_commit(ctx, name, dir, pinned)?;
+		}
+		if bootstrap.review {
+			review_pkgbuild(name, dir)?;
+		}
+	}
+
+	info!("Building and installing {name}");
+	let mut makepkg = ctx.privilege.user_command("makepkg");
+	makepkg.args(["-si", "--noconfirm"]).current_dir(dir);
+	if let Escalation::Doas | Escalation::Run0 = ctx.privilege.escalation {
+		makepkg.env("PACMAN_AUTH", ctx.privilege.escalation.name());
+	}
+	if let Some(ref sources) = ctx.aur_sources {
+		makepkg.env("SRCDEST", sources);
+	}
+	run(ctx, root_file, makepkg)
+}
+
+fn remove_build_dir(ctx: &Context, root_file: &Path, dir: &Path) -> Result<()> {
+	info!("Removing {}", dir.to_string_lossy());
+	let mut rm = ctx.privilege.root_command("rm");
+	rm.arg("-rf").arg(dir);
+	run(ctx, root_file, rm)
+}
+
+/// Fails unless the cloned AUR repo is at the pinned commit, which may be abbreviated.
+fn check_bootstrap_commit(ctx: &Context, name: &str, dir: &Path, pinned: &str) -> Result<()> {
+	// As the user who owns the repo, git refuses to work in repos owned by someone else
+	let mut git = ctx.privilege.user_command("git");
+	let output = git.arg("-C").arg(dir).args(["rev-parse", "HEAD"]).output()?;
+	let head = String::from_utf8_lossy(&output.stdout).trim().to_string();
+	if !output.status.success() || !head.starts_with(pinned) {
+		return Err(Error::BootstrapCommit(name.into(), head, pinned.into()));
+	}
+	info!("{name} is at the pinned commit {pinned}");
+	Ok(())
+}
+
+/// Shows the PKGBUILD and fails unless building it is confirmed.
+fn review_pkgbuild(name: &str, dir: &Path) -> Result<()> {
+	let pkgbuild = std::fs::read_to_string(dir.join("PKGBUILD"))?;
+	println!();
+	for line in pkgbuild.lines() {
+		println!(" |  {line}");
+	}
+	println!();
+	if prompts::confirm(&format!("Build {name} from this PKGBUILD?"))? {
+		Ok(())
+	} else {
+		Err(Error::BootstrapDeclined(name.into()))
+	}
+}
+
+/// Runs steps and keeps track of how they went.
+struct Runner {
+	ctx: Context,
+	default_timeout: Option<Duration>,
+	default_retries: Option<u32>,
+	keepalive: Option<sudo::Keepalive>,
+	keep_going: bool,
+
+	ran: usize,
+	unchanged: usize,
+	skipped: Vec<String>,
+	failures: Vec<Failure>,
+	ignored: Vec<Failure>,
+	/// Handlers to run, in the order they were first notified
+	notified: Vec<String>,
+}
+
+impl Runner {
+	fn new(
+		ctx: Context,
+		default_timeout: Option<Duration>,
+		default_retries: Option<u32>,
+		keepalive: Option<sudo::Keepalive>,
+		keep_going: bool,
+	) -> Self {
+		Self {
+			ctx,
+			default_timeout,
+			default_retries,
+			keepalive,
+			keep_going,
+			ran: 0,
+			unchanged: 0,
+			skipped: vec![],
+			failures: vec![],
+			ignored: vec![],
+			notified: vec![],
+		}
+	}
+
+	/// Runs a step with its timeout and retries. With `--keep-going` or `ignore-errors` failures are recorded
+	/// instead of returned.
+	fn execute(&mut self, step: Step) -> Result<()> {
+		if let Some(ref keepalive) = self.keepalive {
+			keepalive.check()?;
+		}
+
+		let timeout = step.options.timeout.or(self.default_timeout);
+		let retries = step.options.retries.or(self.default_retries).unwrap_or_default();
+		let mut attempt = 0;
+		let result = loop {
+			self.ctx
+				.deadline
+				.set(timeout.map(|timeout| (Instant::now() + timeout, timeout)));
+			let result = run_step(&self.ctx, step.clone());
+			self.ctx.deadline.set(None);
+
+			// If sudo expired while the step ran, that's what made it fail
+			if let Some(ref keepalive) = self.keepalive {
+				keepalive.check()?;
+			}
+
+			match result {
+				Err(err @ (Error::StepFailed(..) | Error::StepTimedOut(..))) if attempt < retries => {
+					let backoff = RETRY_BACKOFF
+						.saturating_mul(2u32.saturating_pow(attempt))
+						.min(MAX_RETRY_BACKOFF);
+					attempt += 1;
+					warn!("{err}");
+					warn!("Retrying in {}s ({attempt}/{retries})", backoff.as_secs());
+					std::thread::sleep(backoff);
 				}
+				result => break result,
+			}
+		};
 
-				if to.exists() {
-					if let Some(to_parent) = to.parent() {
-						run_commands(
-							args.debug,
-							&step.owner,
-							&[format!("mkdir -p {}", to_parent.to_string_lossy())],
-						)?;
+		match result {
+			Ok(StepOutcome::Ran) => {
+				self.ran += 1;
+				for name in step.options.notify {
+					if !self.notified.contains(&name) {
+						self.notified.push(name);
 					}
 				}
+			}
+			Ok(StepOutcome::Unchanged) => self.unchanged += 1,
+			Ok(StepOutcome::Skipped(reason)) => {
+				self.skipped.push(format!(
+					"[{}] {}: {reason}",
+					step.relative_path_str,
+					step.kind.describe()
+				));
+			}
+			Err(err @ Error::SudoExpired) => return Err(err),
+			Err(err) if step.options.ignore_errors => {
+				warn!("{err}");
+				warn!("Ignoring the failure, the step has ignore-errors set");
+				self.ignored.push(Failure::new(&step, &err));
+			}
+			Err(err) if self.keep_going => {
+				error!("{err}");
+				self.failures.push(Failure::new(&step, &err));
+			}
+			Err(err) => return Err(err),
+		}
+
+		Ok(())
+	}
 
-				run_commands(
-					args.debug,
-					&step.owner,
-					&[format!("ln -s {} {}", from.to_string_lossy(), to.to_string_lossy())],
-				)?;
+	fn run_hook(&mut self, name: &str, steps: Vec<Step>) -> Result<()> {
+		if !steps.is_empty() {
+			info!("Running {name} hooks");
+		}
+		for step in steps {
+			self.execute(step)?;
+		}
+		Ok(())
+	}
+
+	fn run_steps(
+		&mut self,
+		steps: Vec<Step>,
+		mut before: BTreeMap<PathBuf, Vec<Step>>,
+		mut after: BTreeMap<PathBuf, Vec<Step>>,
+		mut handlers: BTreeMap<String, Vec<Step>>,
+	) -> Result<()> {
+		// A module's before steps run ahead of its first step, its after steps once its last step ran
+		let last_steps = steps
+			.iter()
+			.enumerate()
+			.map(|(i, step)| (step.owner.clone(), i))
+			.collect::<BTreeMap<_, _>>();
+
+		for (i, step) in steps.into_iter().enumerate() {
+			let owner = step.owner.clone();
+			for step in before.remove(&owner).unwrap_or_default() {
+				self.execute(step)?;
+			}
+			self.execute(step)?;
+			if last_steps.get(&owner) == Some(&i) {
+				for step in after.remove(&owner).unwrap_or_default() {
+					self.execute(step)?;
+				}
 			}
-			StepKind::RunCommand { command } => {
-				info!("[{}] Running command '{}'", step.relative_path_str, &command);
+		}
 
-				run_commands(args.debug, &step.owner, &[command])?;
+		// Handlers can notify other handlers, which then run after them
+		let mut i = 0;
+		while let Some(name) = self.notified.get(i).cloned() {
+			info!("Running handler '{name}'");
+			for step in handlers.remove(&name).unwrap_or_default() {
+				self.execute(step)?;
 			}
-			StepKind::RunCommands { commands } => {
-				info!(
-					"[{}] Running commands:\n{}",
-					step.relative_path_str,
-					commands.join("\n")
-				);
+			i += 1;
+		}
+
+		Ok(())
+	}
+
+	/// Tells the post-run and on-failure hooks how the run went.
+	fn set_status_env(&mut self, error: Option<&Error>) {
+		let failed = error.is_some() || !self.failures.is_empty();
+		let env = &mut self.ctx.env;
+		env.insert(
+			"MROW_RUN_STATUS".into(),
+			if failed { "failure" } else { "success" }.into(),
+		);
+		env.insert("MROW_STEPS_RAN".into(), self.ran.to_string());
+		env.insert("MROW_STEPS_UNCHANGED".into(), self.unchanged.to_string());
+		env.insert("MROW_STEPS_SKIPPED".into(), self.skipped.len().to_string());
+		env.insert(
+			"MROW_STEPS_FAILED".into(),
+			(self.failures.len() + usize::from(error.is_some())).to_string(),
+		);
+		if let Some(error) = error {
+			env.insert("MROW_ERROR".into(), error.to_string());
+		}
+	}
+
+	fn print_summary(&self) {
+		println!();
+		info!(
+			"Done! {} steps ran, {} were already up to date, {} skipped",
+			self.ran,
+			self.unchanged,
+			self.skipped.len()
+		);
+		for skipped in &self.skipped {
+			info!("Skipped {skipped}");
+		}
+		if !self.ignored.is_empty() {
+			warn!("{} step(s) failed but have ignore-errors set", self.ignored.len());
+			for failure in &self.ignored {
+				warn!("[{}] {}: {}", failure.module, failure.step, failure.error);
+			}
+		}
+		if !self.failures.is_empty() {
+			println!();
+			print_failures(&self.failures);
+		}
+	}
+}
+
+fn _main() -> Result<()> {
+	colog::default_builder().filter_level(log::LevelFilter::Debug).init();
+
+	check_os_release()?;
+
+	let args = Args::parse();
+	let base_dir = match args.dir {
+		Some(ref dir) => PathBuf::from(dir).canonicalize()?,
+		None => std::env::current_dir()?,
+	};
+
+	if !base_dir.exists() {
+		error!("Dir '{}' doesn't exist!", base_dir.to_string_lossy());
+		exit(-1);
+	}
+
+	let mut lua = true;
+	let mut root_file = base_dir.join("mrow.luau");
+	if !root_file.exists() {
+		root_file = base_dir.join("mrow.toml");
+		lua = false;
+		if !root_file.exists() {
+			error!("No mrow.toml or mrow.luau found in '{}'", base_dir.to_string_lossy());
+			exit(-1);
+		}
+	}
+
+	let hostname = std::fs::read_to_string("/etc/hostname")?;
+	let hostname = hostname.trim();
 
-				run_commands(args.debug, &step.owner, &commands)?;
+	// Secrets only need the age settings, so don't ask prompts or resolve steps for them
+	if let Some(Command::Secret { action }) = args.command {
+		let (age_identity, age_recipients) = if lua {
+			mrow_lua::age_config(&base_dir, &root_file, hostname)?
+		} else {
+			mrow_toml::age_config(&base_dir, &root_file)?
+		};
+		let age_identity = args.age_identity.or(age_identity);
+		let load_recipients = || {
+			if age_recipients.is_empty() {
+				let identity_path = age_identity.as_deref().ok_or(Error::NoAgeIdentity)?;
+				let identity = secrets::load_identity(identity_path)?;
+				secrets::recipients(identity_path, &identity, &[])
+			} else {
+				Ok(age_recipients.clone())
 			}
-			StepKind::RunScript { path } => {
+		};
+
+		match action {
+			SecretAction::Encrypt { file } => {
+				let out = secrets::encrypt_file(&load_recipients()?, &file)?;
 				info!(
-					"[{}] Running shell script '{}'",
-					step.relative_path_str,
-					path.to_string_lossy()
+					"Encrypted '{}' to '{}'. Delete the plaintext file once you've checked the secret.",
+					file.to_string_lossy(),
+					out.to_string_lossy()
 				);
+			}
+			SecretAction::Edit { file } => {
+				let identity_path = age_identity.as_deref().ok_or(Error::NoAgeIdentity)?;
+				let identity = secrets::load_identity(identity_path)?;
+				let recipients = secrets::recipients(identity_path, &identity, &age_recipients)?;
+				secrets::edit_file(&identity, &recipients, &file)?;
+			}
+		}
+
+		return Ok(());
+	}
+
+	let single_module = args.single_module.and_then(|p| p.canonicalize().ok());
+	let single = single_module.is_some();
+	let mut prompter = prompts::Prompter::new(&base_dir, args.reprompt)?;
+	let (mut plan, config) = if lua {
+		mrow_lua::process(&base_dir, &root_file, single_module, hostname, &mut prompter)?
+	} else {
+		mrow_toml::process(&base_dir, &root_file, single_module, hostname, &mut prompter)?
+	};
+	prompter.save()?;
+	plan.check_handlers()?;
+	plan.check_removals()?;
+	let aur_helper = config.aur_helper;
+	let age_identity = args.age_identity.or(config.age_identity);
+
+	if let Some(Command::Lock) = args.command {
+		return lock_packages(&plan, &base_dir);
+	}
+
+	// pikaur, aura and trizen call sudo themselves and have no option to use anything else
+	if let (
+		Some(escalation @ (Escalation::Doas | Escalation::Run0)),
+		Some(aur_helper @ (AurHelper::Pikaur | AurHelper::Aura | AurHelper::Trizen)),
+	) = (config.escalation, aur_helper)
+	{
+		return Err(Error::AurHelperEscalation(aur_helper.binary(), escalation.name()));
+	}
+
+	let mut ctx = Context {
+		debug: args.debug,
+		privilege: Privilege::detect(config.escalation),
+		aur_helper,
+		identity: None,
+		installed: LocalDb::load().unwrap_or_else(|err| {
+			warn!("Couldn't read the local package database, treating every package as not installed: {err}");
+			LocalDb::default()
+		}),
+		package_cache: args.package_cache,
+		aur_sources: args
+			.download_first
+			.then(|| privilege::home_dir().map(|home| home.join(".cache").join("mrow").join("aur")))
+			.flatten(),
+		aur_pins: Lockfile::load(&base_dir)?
+			.map(|lock| {
+				lock.aur
+					.into_iter()
+					.map(|(package, pin)| (package, pin.commit))
+					.collect()
+			})
+			.unwrap_or_default(),
+		aur_fetched: RefCell::default(),
+		env: config.env.clone(),
+		secret_env: prompter.secret_env().collect(),
+		deadline: Cell::default(),
+		local_packages: RefCell::default(),
+		repos_changed: Cell::default(),
+		sync_policy: config.sync_policy,
+	};
+	ctx.env.insert("MROW_HOSTNAME".into(), hostname.into());
+	ctx.env
+		.insert("MROW_BASE_DIR".into(), base_dir.to_string_lossy().into_owned());
+	ctx.env
+		.insert("MROW_DRY_RUN".into(), if args.debug { "1" } else { "0" }.into());
+	ctx.env.extend(prompter.env());
+	// Commands are printed with their environment in dry-run mode
+	if args.debug {
+		for name in &ctx.secret_env {
+			ctx.env.insert(name.clone(), "<redacted>".into());
+		}
+	}
+	if ctx.privilege.escalation == Escalation::Doas && !ctx.secret_env.is_empty() {
+		warn!(
+			"doas can only pass variables on the command line, where anyone can read them. Steps run as root or another \
+			 user don't get secret answers"
+		);
+	}
+	let username = ctx.privilege.username()?;
 
-				run_command_raw(
-					args.debug,
-					&step.owner,
-					"sh",
-					&[&path.to_string_lossy().into_owned()],
-					&path
-						.parent()
-						.unwrap_or_else(|| {
-							unreachable!(
-								"the program doesn't allow for placing a mrow.toml file in the root of a filesystem"
-							)
-						})
-						.to_string_lossy(),
-				)?;
+	warn!("If the expected username is not '{username}' then CTRL-C and re-run!");
+	println!();
+	if ctx.privilege.escalation != Escalation::None {
+		info!(
+			"Enter your user password. The rest of the install wont require any user interaction unless it fails.Go \
+			 make tea!"
+		);
+	}
+
+	if !args.debug {
+		if let Some(output) = ctx.privilege.authenticate()? {
+			error!("{} elevation failed:", ctx.privilege.escalation.name());
+			error!("{}", String::from_utf8_lossy(output.stderr.as_slice()));
+			exit(-1);
+		}
+	}
+	// doas and run0 can't refresh their credentials without asking again
+	let keepalive = (!args.debug && ctx.privilege.escalation == Escalation::Sudo).then(sudo::Keepalive::start);
+
+	if let Some(Command::Bootstrap) = args.command {
+		let aur_helper = aur_helper.ok_or(Error::NoAurHelper)?;
+		if aur_helper.needs_bootstrap(&ctx.installed) {
+			sync_packages(&ctx, &root_file, config.sync_policy, None)?;
+		}
+		return bootstrap_aur_helper(&ctx, &root_file, aur_helper, &config.aur_bootstrap, &username);
+	}
+
+	if !args.debug && plan.all_steps().any(|s| matches!(s.kind, StepKind::SecretFile { .. })) {
+		let identity_path = age_identity.as_deref().ok_or(Error::NoAgeIdentity)?;
+		ctx.identity = Some(secrets::load_identity(identity_path)?);
+	}
+
+	if aur_helper.is_none() {
+		for step in plan.all_steps() {
+			if let StepKind::InstallPackage { package: _, aur: true }
+			| StepKind::InstallPackages { packages: _, aur: true } = step.kind
+			{
+				error!(
+					"An install package step in '{}' requires AUR but there is no AUR helper set in your mrow.toml",
+					step.relative_path_str
+				);
+				exit(-1);
 			}
 		}
 	}
 
+	// Groups only count as installed when all of their members are, which only the sync databases know
+	if plan_packages(&plan)
+		.iter()
+		.any(|(package, _)| ctx.installed.has_group(package))
+	{
+		match SyncDb::load() {
+			Ok(sync) => ctx.installed.complete_groups(&sync),
+			Err(err) => warn!("Couldn't read the package databases, groups are always passed to pacman: {err}"),
+		}
+	}
+	check_packages(&plan, &ctx.installed)?;
+	check_lock(&plan, &ctx.installed, &base_dir, config.lock_policy, aur_helper)?;
+
+	let mut listed = plan
+		.all_steps()
+		.filter_map(step_packages)
+		.flat_map(|(packages, _)| packages)
+		.chain(&config.ignored_packages)
+		.cloned()
+		.collect::<BTreeSet<_>>();
+	if let Some(aur_helper) = aur_helper {
+		// Installed when bootstrapping the AUR helper. It may also have been installed by its own name, e.g. paru
+		// instead of paru-bin.
+		listed.extend(
+			["base-devel", "git"]
+				.into_iter()
+				.chain(config.aur_bootstrap.package(aur_helper))
+				.chain(aur_helper.package().map(|_| aur_helper.binary()))
+				.map(String::from),
+		);
+	}
+
+	// Nothing is synced when every package is already installed, so a fully applied config doesn't wait on pacman
+	let bootstrap = aur_helper.is_some_and(|aur_helper| aur_helper.needs_bootstrap(&ctx.installed));
+	let mut missing = plan_packages(&plan)
+		.into_iter()
+		.map(|(package, aur)| (package.clone(), aur))
+		.collect::<Vec<_>>();
+	if bootstrap {
+		missing.extend(["base-devel", "git"].map(|package| (package.to_string(), false)));
+	}
+	missing.retain(|(package, _)| !ctx.installed.is_installed(package));
+	// Fetched in advance with --download-first, like AUR packages
+	if let Some(package) = aur_helper.and_then(|aur_helper| config.aur_bootstrap.missing(aur_helper, &ctx.installed)) {
+		missing.push((package.to_string(), true));
+	}
+	let needs_sync = bootstrap || !missing.is_empty();
+	plan.merge_package_steps();
+	let Plan {
+		steps,
+		handlers,
+		before,
+		after,
+		hooks,
+	} = plan;
+	let mut runner = Runner::new(ctx, config.timeout, config.retries, keepalive, args.keep_going);
+
+	println!();
+	let mut result = runner.run_hook("pre-run", hooks.pre_run);
+	if result.is_ok() && needs_sync {
+		let download = args.download_first.then_some(missing.as_slice());
+		result = sync_packages(&runner.ctx, &root_file, config.sync_policy, download);
+	}
+	if let (Ok(()), Some(aur_helper)) = (&result, aur_helper) {
+		result = bootstrap_aur_helper(&runner.ctx, &root_file, aur_helper, &config.aur_bootstrap, &username);
+	}
+	if result.is_ok() {
+		result = runner.run_steps(steps, before, after, handlers);
+	}
+	// With a single module most packages aren't listed
+	if result.is_ok() && runner.failures.is_empty() && !single && config.unlisted_packages != UnlistedPackages::Keep {
+		listed.extend(runner.ctx.local_packages.take());
+		result = prune_packages(&runner.ctx, &root_file, config.unlisted_packages, &listed);
+	}
+
+	let failed = result.is_err() || !runner.failures.is_empty();
+	runner.set_status_env(result.as_ref().err());
+	if failed {
+		// Hook failures are only logged here, the error that failed the run is what gets reported
+		if let Err(err) = runner.run_hook("on-failure", hooks.on_failure) {
+			error!("on-failure hook failed: {err}");
+		}
+	}
+	if let Err(err) = runner.run_hook("post-run", hooks.post_run) {
+		if failed {
+			error!("post-run hook failed: {err}");
+		} else {
+			result = Err(err);
+		}
+	}
+
+	runner.print_summary();
+	result?;
+	if !runner.failures.is_empty() {
+		return Err(Error::StepsFailed(runner.failures.len()));
+	}
+
 	Ok(())
 }
 
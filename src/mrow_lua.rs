@@ -1,8 +1,13 @@
-use mlua::{FromLua, Function, Value};
+use std::{collections::BTreeMap, time::Duration};
+
+use mlua::{FromLua, Function, IntoLua, Value};
 
 use crate::{
-	collapse_path, resolve_path, AurHelper, LazyLock, Lua, Mutex, Path, PathBuf, Rc, Regex, Result, StdLib, Step,
-	StepKind,
+	collapse_path,
+	privilege::Escalation,
+	prompts::{Prompt, PromptKind, Prompter},
+	resolve_path, AurBootstrap, AurHelper, BootstrapCleanup, Config, Guard, Hooks, LazyLock, LockPolicy, Lua, Mutex,
+	Path, PathBuf, Plan, Rc, Regex, Result, RunAs, StdLib, Step, StepKind, StepOptions, SyncPolicy, UnlistedPackages,
 };
 
 impl<'lua> FromLua<'lua> for AurHelper {
@@ -18,20 +23,342 @@ impl<'lua> FromLua<'lua> for AurHelper {
 		Ok(match str {
 			"yay" => AurHelper::Yay,
 			"paru" => AurHelper::Paru,
+			"pikaur" => AurHelper::Pikaur,
+			"aura" => AurHelper::Aura,
+			"trizen" => AurHelper::Trizen,
+			"makepkg" => AurHelper::Makepkg,
 			v => {
 				return Err(mlua::Error::FromLuaConversionError {
 					from: value.type_name(),
 					to: "AurHelper",
-					message: Some(format!("Expected 'yay' or 'paru'. Got '{v}'")),
+					message: Some(format!(
+						"Expected 'yay', 'paru', 'pikaur', 'aura', 'trizen' or 'makepkg'. Got '{v}'"
+					)),
 				})
 			}
 		})
 	}
 }
 
+impl<'lua> FromLua<'lua> for BootstrapCleanup {
+	fn from_lua(value: mlua::Value<'lua>, _lua: &'lua Lua) -> mlua::Result<Self> {
+		let Some(str) = value.as_str() else {
+			return Err(mlua::Error::FromLuaConversionError {
+				from: value.type_name(),
+				to: "BootstrapCleanup",
+				message: None,
+			});
+		};
+
+		Ok(match str {
+			"never" => BootstrapCleanup::Never,
+			"on-success" => BootstrapCleanup::OnSuccess,
+			"always" => BootstrapCleanup::Always,
+			v => {
+				return Err(mlua::Error::FromLuaConversionError {
+					from: value.type_name(),
+					to: "BootstrapCleanup",
+					message: Some(format!("Expected 'never', 'on-success' or 'always'. Got '{v}'")),
+				})
+			}
+		})
+	}
+}
+
+impl<'lua> FromLua<'lua> for AurBootstrap {
+	fn from_lua(value: mlua::Value<'lua>, _lua: &'lua Lua) -> mlua::Result<Self> {
+		match value {
+			Value::Table(table) => Ok(Self {
+				package: table.get("package")?,
+				build_dir: table.get::<_, Option<String>>("build_dir")?.map(PathBuf::from),
+				cleanup: table.get::<_, Option<_>>("cleanup")?.unwrap_or_default(),
+				review: table.get::<_, Option<_>>("review")?.unwrap_or_default(),
+				commit: table.get("commit")?,
+			}),
+			_ => Err(mlua::Error::FromLuaConversionError {
+				from: value.type_name(),
+				to: "AurBootstrap",
+				message: None,
+			}),
+		}
+	}
+}
+
+impl<'lua> FromLua<'lua> for SyncPolicy {
+	fn from_lua(value: mlua::Value<'lua>, _lua: &'lua Lua) -> mlua::Result<Self> {
+		let Some(str) = value.as_str() else {
+			return Err(mlua::Error::FromLuaConversionError {
+				from: value.type_name(),
+				to: "SyncPolicy",
+				message: None,
+			});
+		};
+
+		Ok(match str {
+			"upgrade" => SyncPolicy::Upgrade,
+			"sync" => SyncPolicy::Sync,
+			"never" => SyncPolicy::Never,
+			v => {
+				return Err(mlua::Error::FromLuaConversionError {
+					from: value.type_name(),
+					to: "SyncPolicy",
+					message: Some(format!("Expected 'upgrade', 'sync' or 'never'. Got '{v}'")),
+				})
+			}
+		})
+	}
+}
+
+impl<'lua> FromLua<'lua> for LockPolicy {
+	fn from_lua(value: mlua::Value<'lua>, _lua: &'lua Lua) -> mlua::Result<Self> {
+		let Some(str) = value.as_str() else {
+			return Err(mlua::Error::FromLuaConversionError {
+				from: value.type_name(),
+				to: "LockPolicy",
+				message: None,
+			});
+		};
+
+		Ok(match str {
+			"ignore" => LockPolicy::Ignore,
+			"report" => LockPolicy::Report,
+			"fail" => LockPolicy::Fail,
+			v => {
+				return Err(mlua::Error::FromLuaConversionError {
+					from: value.type_name(),
+					to: "LockPolicy",
+					message: Some(format!("Expected 'ignore', 'report' or 'fail'. Got '{v}'")),
+				})
+			}
+		})
+	}
+}
+
+impl<'lua> FromLua<'lua> for UnlistedPackages {
+	fn from_lua(value: mlua::Value<'lua>, _lua: &'lua Lua) -> mlua::Result<Self> {
+		let Some(str) = value.as_str() else {
+			return Err(mlua::Error::FromLuaConversionError {
+				from: value.type_name(),
+				to: "UnlistedPackages",
+				message: None,
+			});
+		};
+
+		Ok(match str {
+			"keep" => UnlistedPackages::Keep,
+			"mark" => UnlistedPackages::Mark,
+			"remove" => UnlistedPackages::Remove,
+			v => {
+				return Err(mlua::Error::FromLuaConversionError {
+					from: value.type_name(),
+					to: "UnlistedPackages",
+					message: Some(format!("Expected 'keep', 'mark' or 'remove'. Got '{v}'")),
+				})
+			}
+		})
+	}
+}
+
+impl<'lua> FromLua<'lua> for StepOptions {
+	fn from_lua(value: mlua::Value<'lua>, _lua: &'lua Lua) -> mlua::Result<Self> {
+		match value {
+			// copy_file and secret_file used to take an 'as_root' boolean in this position
+			Value::Boolean(as_root) => Ok(Self {
+				run_as: if as_root { RunAs::Root } else { RunAs::User },
+				..Default::default()
+			}),
+			Value::Table(table) => {
+				let as_root = table.get::<_, Option<bool>>("as_root")?.unwrap_or_default();
+				let as_user = table.get::<_, Option<String>>("as_user")?;
+				let run_as = match (as_root, as_user) {
+					(true, Some(_)) => {
+						return Err(mlua::Error::FromLuaConversionError {
+							from: "table",
+							to: "StepOptions",
+							message: Some("'as_root' and 'as_user' can't be used together".into()),
+						})
+					}
+					(true, None) => RunAs::Root,
+					(false, Some(user)) => RunAs::Other(user),
+					(false, None) => RunAs::User,
+				};
+
+				let notify = table.get::<_, Option<Vec<String>>>("notify")?.unwrap_or_default();
+
+				let timeout = table.get::<_, Option<u64>>("timeout")?.map(Duration::from_secs);
+				let retries = table.get("retries")?;
+				let ignore_errors = table.get::<_, Option<bool>>("ignore_errors")?.unwrap_or_default();
+
+				Ok(Self {
+					run_as,
+					notify,
+					timeout,
+					retries,
+					ignore_errors,
+				})
+			}
+			_ => Err(mlua::Error::FromLuaConversionError {
+				from: value.type_name(),
+				to: "StepOptions",
+				message: None,
+			}),
+		}
+	}
+}
+
+/// The options of the command and script functions that aren't common to every step.
+struct ProcessOptions {
+	interpreter: Option<String>,
+	args: Vec<String>,
+	env: BTreeMap<String, String>,
+	cwd: Option<String>,
+	creates: Option<String>,
+	unless: Option<String>,
+	onlyif: Option<String>,
+}
+
+impl ProcessOptions {
+	fn guard(&self, parent: &Path) -> Guard {
+		Guard {
+			creates: self.creates.as_ref().map(|creates| resolve_path(creates, parent)),
+			unless: self.unless.clone(),
+			onlyif: self.onlyif.clone(),
+		}
+	}
+}
+
+/// Options of `remove_package` and `remove_packages`, next to the step options.
+#[derive(Default)]
+struct RemoveOptions {
+	dependencies: bool,
+	config_files: bool,
+}
+
+impl<'lua> FromLua<'lua> for RemoveOptions {
+	fn from_lua(value: mlua::Value<'lua>, _lua: &'lua Lua) -> mlua::Result<Self> {
+		match value {
+			Value::Nil => Ok(Self::default()),
+			Value::Table(table) => Ok(Self {
+				dependencies: table.get::<_, Option<bool>>("dependencies")?.unwrap_or_default(),
+				config_files: table.get::<_, Option<bool>>("config_files")?.unwrap_or_default(),
+			}),
+			_ => Err(mlua::Error::FromLuaConversionError {
+				from: value.type_name(),
+				to: "RemoveOptions",
+				message: None,
+			}),
+		}
+	}
+}
+
+/// The repo passed to `pacman_repo`, next to the step options.
+struct RepoOptions {
+	servers: Vec<String>,
+	include: Option<String>,
+	sig_level: Option<String>,
+	key: Option<String>,
+	keyserver: Option<String>,
+}
+
+impl<'lua> FromLua<'lua> for RepoOptions {
+	fn from_lua(value: mlua::Value<'lua>, _lua: &'lua Lua) -> mlua::Result<Self> {
+		match value {
+			Value::Table(table) => {
+				let servers = table.get::<_, Option<Vec<String>>>("servers")?.unwrap_or_default();
+				let include = table.get::<_, Option<String>>("include")?;
+				if servers.is_empty() && include.is_none() {
+					return Err(mlua::Error::FromLuaConversionError {
+						from: "table",
+						to: "PacmanRepo",
+						message: Some("'servers' or 'include' has to be set".into()),
+					});
+				}
+				Ok(Self {
+					servers,
+					include,
+					sig_level: table.get("sig_level")?,
+					key: table.get("key")?,
+					keyserver: table.get("keyserver")?,
+				})
+			}
+			_ => Err(mlua::Error::FromLuaConversionError {
+				from: value.type_name(),
+				to: "PacmanRepo",
+				message: None,
+			}),
+		}
+	}
+}
+
+impl<'lua> FromLua<'lua> for ProcessOptions {
+	fn from_lua(value: mlua::Value<'lua>, _lua: &'lua Lua) -> mlua::Result<Self> {
+		match value {
+			Value::Nil => Ok(Self {
+				interpreter: None,
+				args: vec![],
+				env: BTreeMap::new(),
+				cwd: None,
+				creates: None,
+				unless: None,
+				onlyif: None,
+			}),
+			Value::Table(table) => Ok(Self {
+				interpreter: table.get("interpreter")?,
+				args: table.get::<_, Option<Vec<String>>>("args")?.unwrap_or_default(),
+				env: table
+					.get::<_, Option<BTreeMap<String, String>>>("env")?
+					.unwrap_or_default(),
+				cwd: table.get("cwd")?,
+				creates: table.get("creates")?,
+				unless: table.get("unless")?,
+				onlyif: table.get("onlyif")?,
+			}),
+			_ => Err(mlua::Error::FromLuaConversionError {
+				from: value.type_name(),
+				to: "ProcessOptions",
+				message: None,
+			}),
+		}
+	}
+}
+
 struct MrowRoot<'lua> {
 	init: Function<'lua>,
 	aur_helper: Option<AurHelper>,
+	sync_policy: Option<SyncPolicy>,
+	lock_policy: Option<LockPolicy>,
+	unlisted_packages: Option<UnlistedPackages>,
+	ignored_packages: Option<Vec<String>>,
+	aur_bootstrap: Option<AurBootstrap>,
+	privilege_escalation: Option<Escalation>,
+	age_identity: Option<String>,
+	env: Option<BTreeMap<String, String>>,
+	timeout: Option<u64>,
+	retries: Option<u32>,
+	hooks: Option<LuaHooks<'lua>>,
+}
+
+struct LuaHooks<'lua> {
+	pre_run: Option<Function<'lua>>,
+	post_run: Option<Function<'lua>>,
+	on_failure: Option<Function<'lua>>,
+}
+
+impl<'lua> FromLua<'lua> for LuaHooks<'lua> {
+	fn from_lua(value: mlua::Value<'lua>, _lua: &'lua Lua) -> mlua::Result<Self> {
+		match value {
+			Value::Table(table) => Ok(Self {
+				pre_run: table.get("pre_run")?,
+				post_run: table.get("post_run")?,
+				on_failure: table.get("on_failure")?,
+			}),
+			_ => Err(mlua::Error::FromLuaConversionError {
+				from: value.type_name(),
+				to: "Hooks",
+				message: None,
+			}),
+		}
+	}
 }
 
 impl<'lua> FromLua<'lua> for MrowRoot<'lua> {
@@ -40,7 +367,32 @@ impl<'lua> FromLua<'lua> for MrowRoot<'lua> {
 			Value::Table(table) => {
 				let init = table.get("init")?;
 				let aur_helper = table.get("aur_helper")?;
-				Ok(Self { init, aur_helper })
+				let sync_policy = table.get("sync_policy")?;
+				let lock_policy = table.get("lock_policy")?;
+				let unlisted_packages = table.get("unlisted_packages")?;
+				let ignored_packages = table.get("ignored_packages")?;
+				let aur_bootstrap = table.get("aur_bootstrap")?;
+				let privilege_escalation = table.get("privilege_escalation")?;
+				let age_identity = table.get("age_identity")?;
+				let env = table.get("env")?;
+				let timeout = table.get("timeout")?;
+				let retries = table.get("retries")?;
+				let hooks = table.get("hooks")?;
+				Ok(Self {
+					init,
+					aur_helper,
+					sync_policy,
+					lock_policy,
+					unlisted_packages,
+					ignored_packages,
+					aur_bootstrap,
+					privilege_escalation,
+					age_identity,
+					env,
+					timeout,
+					retries,
+					hooks,
+				})
 			}
 			_ => Err(mlua::Error::FromLuaConversionError {
 				from: value.type_name(),
@@ -80,13 +432,27 @@ fn get_function_caller_path(lua: &Lua, base_dir: &Path, exec_single: &Rc<Option<
 	})
 }
 
+/// Calls `define` and takes the steps it added.
+fn capture_steps(steps: &Mutex<Vec<Step>>, define: &Function) -> mlua::Result<Vec<Step>> {
+	let start = steps.lock().map_err(|e| mlua::Error::runtime(e.to_string()))?.len();
+	define.call::<_, ()>(())?;
+	Ok(steps
+		.lock()
+		.map_err(|e| mlua::Error::runtime(e.to_string()))?
+		.split_off(start))
+}
+
 pub fn process(
-	base_dir: PathBuf,
+	base_dir: &Path,
 	root_file: &Path,
 	exec_single: Option<PathBuf>,
 	hostname: &str,
-) -> Result<(Vec<Step>, Option<AurHelper>)> {
+	prompter: &mut Prompter,
+) -> Result<(Plan, Config)> {
+	let base_dir = base_dir.to_path_buf();
 	let steps: Rc<Mutex<Vec<Step>>> = Rc::default();
+	// Holds handlers and before/after steps, steps are collected separately
+	let plan: Rc<Mutex<Plan>> = Rc::default();
 	let exec_single: Rc<Option<PathBuf>> = Rc::new(exec_single);
 
 	let lua = Lua::new();
@@ -106,12 +472,76 @@ pub fn process(
 		let exec_single = exec_single.clone();
 		mrow_export.set(
 			"install_package",
-			lua.create_function(move |lua, (package, aur): (String, Option<bool>)| {
+			lua.create_function(
+				move |lua, (package, aur, opts): (String, Option<bool>, Option<StepOptions>)| {
+					let owner = get_function_caller_path(lua, &base_dir, &exec_single)?;
+					let relative_path_str = collapse_path(&base_dir, &owner).to_string_lossy().into_owned();
+					let kind = StepKind::InstallPackage {
+						package,
+						aur: aur.unwrap_or_default(),
+					};
+					steps
+						.lock()
+						.map_err(|e| mlua::Error::runtime(e.to_string()))?
+						.push(Step {
+							owner,
+							relative_path_str,
+							kind,
+							options: opts.unwrap_or_default(),
+						});
+					Ok(())
+				},
+			)?,
+		)?;
+	}
+
+	// Install packages
+	{
+		let base_dir = base_dir.clone();
+		let steps = steps.clone();
+		let exec_single = exec_single.clone();
+		mrow_export.set(
+			"install_packages",
+			lua.create_function(
+				move |lua, (packages, aur, opts): (Vec<String>, Option<bool>, Option<StepOptions>)| {
+					let owner = get_function_caller_path(lua, &base_dir, &exec_single)?;
+					let relative_path_str = collapse_path(&base_dir, &owner).to_string_lossy().into_owned();
+					let kind = StepKind::InstallPackages {
+						packages,
+						aur: aur.unwrap_or_default(),
+					};
+					steps
+						.lock()
+						.map_err(|e| mlua::Error::runtime(e.to_string()))?
+						.push(Step {
+							owner,
+							relative_path_str,
+							kind,
+							options: opts.unwrap_or_default(),
+						});
+					Ok(())
+				},
+			)?,
+		)?;
+	}
+
+	// Remove package
+	{
+		let base_dir = base_dir.clone();
+		let steps = steps.clone();
+		let exec_single = exec_single.clone();
+		mrow_export.set(
+			"remove_package",
+			lua.create_function(move |lua, (package, opts): (String, Value)| {
 				let owner = get_function_caller_path(lua, &base_dir, &exec_single)?;
 				let relative_path_str = collapse_path(&base_dir, &owner).to_string_lossy().into_owned();
-				let kind = StepKind::InstallPackage {
+
+				let remove = RemoveOptions::from_lua(opts.clone(), lua)?;
+				let opts = Option::<StepOptions>::from_lua(opts, lua)?;
+				let kind = StepKind::RemovePackage {
 					package,
-					aur: aur.unwrap_or_default(),
+					dependencies: remove.dependencies,
+					config_files: remove.config_files,
 				};
 				steps
 					.lock()
@@ -120,25 +550,30 @@ pub fn process(
 						owner,
 						relative_path_str,
 						kind,
+						options: opts.unwrap_or_default(),
 					});
 				Ok(())
 			})?,
 		)?;
 	}
 
-	// Install packages
+	// Remove packages
 	{
 		let base_dir = base_dir.clone();
 		let steps = steps.clone();
 		let exec_single = exec_single.clone();
 		mrow_export.set(
-			"install_packages",
-			lua.create_function(move |lua, (packages, aur): (Vec<String>, Option<bool>)| {
+			"remove_packages",
+			lua.create_function(move |lua, (packages, opts): (Vec<String>, Value)| {
 				let owner = get_function_caller_path(lua, &base_dir, &exec_single)?;
 				let relative_path_str = collapse_path(&base_dir, &owner).to_string_lossy().into_owned();
-				let kind = StepKind::InstallPackages {
+
+				let remove = RemoveOptions::from_lua(opts.clone(), lua)?;
+				let opts = Option::<StepOptions>::from_lua(opts, lua)?;
+				let kind = StepKind::RemovePackages {
 					packages,
-					aur: aur.unwrap_or_default(),
+					dependencies: remove.dependencies,
+					config_files: remove.config_files,
 				};
 				steps
 					.lock()
@@ -147,6 +582,76 @@ pub fn process(
 						owner,
 						relative_path_str,
 						kind,
+						options: opts.unwrap_or_default(),
+					});
+				Ok(())
+			})?,
+		)?;
+	}
+
+	// Local package
+	{
+		let base_dir = base_dir.clone();
+		let steps = steps.clone();
+		let exec_single = exec_single.clone();
+		mrow_export.set(
+			"local_package",
+			lua.create_function(move |lua, (path, opts): (String, Option<StepOptions>)| {
+				let owner = get_function_caller_path(lua, &base_dir, &exec_single)?;
+				let Some(parent) = owner.parent() else { unreachable!() };
+				let relative_path_str = collapse_path(&base_dir, &owner).to_string_lossy().into_owned();
+
+				let path = resolve_path(&path, parent);
+				if !path.join("PKGBUILD").is_file() {
+					return Err(mlua::Error::runtime(format!(
+						"No PKGBUILD in '{}'",
+						path.to_string_lossy()
+					)));
+				}
+
+				steps
+					.lock()
+					.map_err(|e| mlua::Error::runtime(e.to_string()))?
+					.push(Step {
+						owner,
+						relative_path_str,
+						kind: StepKind::LocalPackage { path },
+						options: opts.unwrap_or_default(),
+					});
+				Ok(())
+			})?,
+		)?;
+	}
+
+	// Pacman repo
+	{
+		let base_dir = base_dir.clone();
+		let steps = steps.clone();
+		let exec_single = exec_single.clone();
+		mrow_export.set(
+			"pacman_repo",
+			lua.create_function(move |lua, (name, repo): (String, Value)| {
+				let owner = get_function_caller_path(lua, &base_dir, &exec_single)?;
+				let relative_path_str = collapse_path(&base_dir, &owner).to_string_lossy().into_owned();
+
+				let opts = Option::<StepOptions>::from_lua(repo.clone(), lua)?;
+				let repo = RepoOptions::from_lua(repo, lua)?;
+				let kind = StepKind::PacmanRepo {
+					name,
+					servers: repo.servers,
+					include: repo.include,
+					sig_level: repo.sig_level,
+					key: repo.key,
+					keyserver: repo.keyserver,
+				};
+				steps
+					.lock()
+					.map_err(|e| mlua::Error::runtime(e.to_string()))?
+					.push(Step {
+						owner,
+						relative_path_str,
+						kind,
+						options: opts.unwrap_or_default(),
 					});
 				Ok(())
 			})?,
@@ -160,14 +665,13 @@ pub fn process(
 		let exec_single = exec_single.clone();
 		mrow_export.set(
 			"copy_file",
-			lua.create_function(move |lua, (from, to, as_root): (String, String, Option<bool>)| {
+			lua.create_function(move |lua, (from, to, opts): (String, String, Option<StepOptions>)| {
 				let owner = get_function_caller_path(lua, &base_dir, &exec_single)?;
 				let Some(parent) = owner.parent() else { unreachable!() };
 				let relative_path_str = collapse_path(&base_dir, &owner).to_string_lossy().into_owned();
 				let kind = StepKind::CopyFile {
 					from: resolve_path(&from, parent),
 					to: resolve_path(&to, parent),
-					as_root: as_root.unwrap_or_default(),
 				};
 				steps
 					.lock()
@@ -176,6 +680,36 @@ pub fn process(
 						owner,
 						relative_path_str,
 						kind,
+						options: opts.unwrap_or_default(),
+					});
+				Ok(())
+			})?,
+		)?;
+	}
+
+	// Secret file
+	{
+		let base_dir = base_dir.clone();
+		let steps = steps.clone();
+		let exec_single = exec_single.clone();
+		mrow_export.set(
+			"secret_file",
+			lua.create_function(move |lua, (from, to, opts): (String, String, Option<StepOptions>)| {
+				let owner = get_function_caller_path(lua, &base_dir, &exec_single)?;
+				let Some(parent) = owner.parent() else { unreachable!() };
+				let relative_path_str = collapse_path(&base_dir, &owner).to_string_lossy().into_owned();
+				let kind = StepKind::SecretFile {
+					from: resolve_path(&from, parent),
+					to: resolve_path(&to, parent),
+				};
+				steps
+					.lock()
+					.map_err(|e| mlua::Error::runtime(e.to_string()))?
+					.push(Step {
+						owner,
+						relative_path_str,
+						kind,
+						options: opts.unwrap_or_default(),
 					});
 				Ok(())
 			})?,
@@ -190,7 +724,7 @@ pub fn process(
 		mrow_export.set(
 			"symlink",
 			lua.create_function(
-				move |lua, (from, to, delete_existing): (String, String, Option<bool>)| {
+				move |lua, (from, to, delete_existing, opts): (String, String, Option<bool>, Option<StepOptions>)| {
 					let owner = get_function_caller_path(lua, &base_dir, &exec_single)?;
 					let Some(parent) = owner.parent() else { unreachable!() };
 					let relative_path_str = collapse_path(&base_dir, &owner).to_string_lossy().into_owned();
@@ -206,6 +740,7 @@ pub fn process(
 							owner,
 							relative_path_str,
 							kind,
+							options: opts.unwrap_or_default(),
 						});
 					Ok(())
 				},
@@ -220,10 +755,20 @@ pub fn process(
 		let exec_single = exec_single.clone();
 		mrow_export.set(
 			"run_command",
-			lua.create_function(move |lua, command: String| {
+			lua.create_function(move |lua, (command, opts): (String, Value)| {
 				let owner = get_function_caller_path(lua, &base_dir, &exec_single)?;
+				let Some(parent) = owner.parent() else { unreachable!() };
 				let relative_path_str = collapse_path(&base_dir, &owner).to_string_lossy().into_owned();
-				let kind = StepKind::RunCommand { command };
+
+				let process = ProcessOptions::from_lua(opts.clone(), lua)?;
+				let opts = Option::<StepOptions>::from_lua(opts, lua)?;
+				let guard = process.guard(parent);
+				let kind = StepKind::RunCommand {
+					command,
+					env: process.env,
+					cwd: process.cwd.map(|cwd| resolve_path(&cwd, parent)),
+					guard,
+				};
 				steps
 					.lock()
 					.map_err(|e| mlua::Error::runtime(e.to_string()))?
@@ -231,6 +776,7 @@ pub fn process(
 						owner,
 						relative_path_str,
 						kind,
+						options: opts.unwrap_or_default(),
 					});
 				Ok(())
 			})?,
@@ -244,10 +790,20 @@ pub fn process(
 		let exec_single = exec_single.clone();
 		mrow_export.set(
 			"run_commands",
-			lua.create_function(move |lua, commands: Vec<String>| {
+			lua.create_function(move |lua, (commands, opts): (Vec<String>, Value)| {
 				let owner = get_function_caller_path(lua, &base_dir, &exec_single)?;
+				let Some(parent) = owner.parent() else { unreachable!() };
 				let relative_path_str = collapse_path(&base_dir, &owner).to_string_lossy().into_owned();
-				let kind = StepKind::RunCommands { commands };
+
+				let process = ProcessOptions::from_lua(opts.clone(), lua)?;
+				let opts = Option::<StepOptions>::from_lua(opts, lua)?;
+				let guard = process.guard(parent);
+				let kind = StepKind::RunCommands {
+					commands,
+					env: process.env,
+					cwd: process.cwd.map(|cwd| resolve_path(&cwd, parent)),
+					guard,
+				};
 				steps
 					.lock()
 					.map_err(|e| mlua::Error::runtime(e.to_string()))?
@@ -255,6 +811,7 @@ pub fn process(
 						owner,
 						relative_path_str,
 						kind,
+						options: opts.unwrap_or_default(),
 					});
 				Ok(())
 			})?,
@@ -268,12 +825,65 @@ pub fn process(
 		let exec_single = exec_single.clone();
 		mrow_export.set(
 			"run_script",
-			lua.create_function(move |lua, path: String| {
+			lua.create_function(move |lua, (path, opts): (String, Value)| {
 				let owner = get_function_caller_path(lua, &base_dir, &exec_single)?;
 				let Some(parent) = owner.parent() else { unreachable!() };
 				let relative_path_str = collapse_path(&base_dir, &owner).to_string_lossy().into_owned();
+
+				let path = resolve_path(&path, parent);
+				if !path.is_file() {
+					return Err(mlua::Error::runtime(format!(
+						"Script '{}' doesn't exist",
+						path.to_string_lossy()
+					)));
+				}
+
+				let script = ProcessOptions::from_lua(opts.clone(), lua)?;
+				let opts = Option::<StepOptions>::from_lua(opts, lua)?;
+				let guard = script.guard(parent);
 				let kind = StepKind::RunScript {
-					path: resolve_path(&path, &parent),
+					path,
+					interpreter: script.interpreter,
+					args: script.args,
+					env: script.env,
+					cwd: script.cwd.map(|cwd| resolve_path(&cwd, parent)),
+					guard,
+				};
+				steps
+					.lock()
+					.map_err(|e| mlua::Error::runtime(e.to_string()))?
+					.push(Step {
+						owner,
+						relative_path_str,
+						kind,
+						options: opts.unwrap_or_default(),
+					});
+				Ok(())
+			})?,
+		)?;
+	}
+
+	// Inline script
+	{
+		let base_dir = base_dir.clone();
+		let steps = steps.clone();
+		let exec_single = exec_single.clone();
+		mrow_export.set(
+			"script",
+			lua.create_function(move |lua, (body, interpreter, opts): (String, Option<String>, Value)| {
+				let owner = get_function_caller_path(lua, &base_dir, &exec_single)?;
+				let Some(parent) = owner.parent() else { unreachable!() };
+				let relative_path_str = collapse_path(&base_dir, &owner).to_string_lossy().into_owned();
+
+				let script = ProcessOptions::from_lua(opts.clone(), lua)?;
+				let opts = Option::<StepOptions>::from_lua(opts, lua)?;
+				let guard = script.guard(parent);
+				let kind = StepKind::InlineScript {
+					body,
+					interpreter: interpreter.or(script.interpreter),
+					env: script.env,
+					cwd: script.cwd.map(|cwd| resolve_path(&cwd, parent)),
+					guard,
 				};
 				steps
 					.lock()
@@ -282,16 +892,59 @@ pub fn process(
 						owner,
 						relative_path_str,
 						kind,
+						options: opts.unwrap_or_default(),
 					});
 				Ok(())
 			})?,
 		)?;
 	}
 
+	// Handler
+	{
+		let steps = steps.clone();
+		let plan = plan.clone();
+		mrow_export.set(
+			"handler",
+			lua.create_function(move |_, (name, define): (String, Function)| {
+				// Steps added while the function runs belong to the handler
+				let handler_steps = capture_steps(&steps, &define)?;
+				plan.lock()
+					.map_err(|e| mlua::Error::runtime(e.to_string()))?
+					.add_handler(name, handler_steps)
+					.map_err(mlua::Error::external)?;
+				Ok(())
+			})?,
+		)?;
+	}
+
+	// Before & After
+	for name in ["before", "after"] {
+		let base_dir = base_dir.clone();
+		let steps = steps.clone();
+		let plan = plan.clone();
+		let exec_single = exec_single.clone();
+		mrow_export.set(
+			name,
+			lua.create_function(move |lua, define: Function| {
+				let owner = get_function_caller_path(lua, &base_dir, &exec_single)?;
+				let module_steps = capture_steps(&steps, &define)?;
+				let mut plan = plan.lock().map_err(|e| mlua::Error::runtime(e.to_string()))?;
+				let modules = if name == "before" {
+					&mut plan.before
+				} else {
+					&mut plan.after
+				};
+				modules.entry(owner).or_default().extend(module_steps);
+				Ok(())
+			})?,
+		)?;
+	}
+
 	lua.globals().set("mrow", mrow_export)?;
 	lua.globals()
 		.set("_require", lua.globals().raw_get::<_, mlua::Function>("require")?)?;
 	{
+		let base_dir = base_dir.clone();
 		let exec_single = exec_single.clone();
 		lua.globals().set(
 			"require",
@@ -327,13 +980,120 @@ pub fn process(
 	lua.globals().set("log_debug", create_log_fn(log::Level::Debug)?)?;
 	lua.globals().set("log_error", create_log_fn(log::Level::Error)?)?;
 
-	let root = lua.load(std::fs::read_to_string(root_file)?).eval::<MrowRoot>()?;
-	if let Some(ref exec_single) = *exec_single {
-		lua.load(std::fs::read_to_string(exec_single)?).eval::<()>()?;
-	} else {
-		root.init.call::<_, ()>(())?;
-	}
+	let root_source = std::fs::read_to_string(root_file)?;
+	let single_source = match *exec_single {
+		Some(ref exec_single) => Some(std::fs::read_to_string(exec_single)?),
+		None => None,
+	};
+
+	// Prompt borrows the prompter, so it only exists while the config is being evaluated
+	let root = lua.scope(|scope| {
+		lua.globals().get::<_, mlua::Table>("mrow")?.set(
+			"prompt",
+			scope.create_function_mut(|lua, prompt: Prompt| {
+				let answer = prompter.ask(&prompt).map_err(mlua::Error::external)?;
+				if prompt.kind == PromptKind::Confirm {
+					(answer == "true").into_lua(lua)
+				} else {
+					answer.into_lua(lua)
+				}
+			})?,
+		)?;
+
+		let root = lua.load(root_source).eval::<MrowRoot>()?;
+		if let Some(single_source) = single_source {
+			lua.load(single_source).eval::<()>()?;
+		} else {
+			root.init.call::<_, ()>(())?;
+		}
+
+		// Hooks are defined by the root, so they also run when only a single module is executed
+		let mut hooks = Hooks::default();
+		if let Some(ref lua_hooks) = root.hooks {
+			let capture = |define: &Option<Function>| match define {
+				Some(define) => capture_steps(&steps, define),
+				None => Ok(vec![]),
+			};
+			hooks = Hooks {
+				pre_run: capture(&lua_hooks.pre_run)?,
+				post_run: capture(&lua_hooks.post_run)?,
+				on_failure: capture(&lua_hooks.on_failure)?,
+			};
+		}
+		Ok((root, hooks))
+	})?;
+	let (root, hooks) = root;
+
+	let mut plan = std::mem::take(&mut *plan.lock().unwrap());
+	plan.steps = std::mem::take(&mut *steps.lock().unwrap());
+	plan.hooks = hooks;
+	let config = Config {
+		aur_helper: root.aur_helper,
+		sync_policy: root.sync_policy.unwrap_or_default(),
+		lock_policy: root.lock_policy.unwrap_or_default(),
+		unlisted_packages: root.unlisted_packages.unwrap_or_default(),
+		ignored_packages: root.ignored_packages.unwrap_or_default(),
+		aur_bootstrap: root
+			.aur_bootstrap
+			.map(|bootstrap| AurBootstrap {
+				build_dir: bootstrap
+					.build_dir
+					.map(|p| resolve_path(&p.to_string_lossy(), &base_dir)),
+				..bootstrap
+			})
+			.unwrap_or_default(),
+		escalation: root.privilege_escalation,
+		age_identity: root.age_identity.map(|p| resolve_path(&p, &base_dir)),
+		env: root.env.unwrap_or_default(),
+		timeout: root.timeout.map(Duration::from_secs),
+		retries: root.retries,
+	};
+	Ok((plan, config))
+}
+
+/// Reads only `age_identity` and `age_recipients` from the root for `mrow secret`. `init` isn't called and prompts
+/// aren't asked, `mrow.prompt` returns the prompt's default instead.
+pub fn age_config(base_dir: &Path, root_file: &Path, hostname: &str) -> Result<(Option<PathBuf>, Vec<String>)> {
+	let lua = Lua::new();
+	lua.sandbox(true)?;
+	lua.load_from_std_lib(StdLib::ALL)?;
+	lua.load(
+		r"function install_package() end
+		function log_info() end
+		function log_warn() end
+		function log_debug() end
+		function log_error() end",
+	)
+	.eval::<()>()?;
+
+	let mrow_export = lua.create_table()?;
+	mrow_export.set("hostname", hostname)?;
+	mrow_export.set("base_dir", base_dir.to_string_lossy().trim())?;
+	mrow_export.set(
+		"prompt",
+		lua.create_function(|lua, prompt: Prompt| {
+			let answer = prompt.default.unwrap_or_default();
+			if prompt.kind == PromptKind::Confirm {
+				(answer == "true").into_lua(lua)
+			} else {
+				answer.into_lua(lua)
+			}
+		})?,
+	)?;
+	// Every other function only collects steps, which aren't needed here
+	let stubs = lua.create_table()?;
+	stubs.set(
+		"__index",
+		lua.load("function() return function() end end").eval::<Function>()?,
+	)?;
+	mrow_export.set_metatable(Some(stubs));
+	lua.globals().set("mrow", mrow_export)?;
 
-	let steps = std::mem::take(&mut *steps.lock().unwrap());
-	Ok((steps, root.aur_helper))
+	let root = lua.load(std::fs::read_to_string(root_file)?).eval::<mlua::Table>()?;
+	let age_identity = root.get::<_, Option<String>>("age_identity")?;
+	let age_recipients = root.get::<_, Option<Vec<String>>>("age_recipients")?;
+	Ok((
+		age_identity.map(|p| resolve_path(&p, base_dir)),
+		age_recipients.unwrap_or_default(),
+	))
 }
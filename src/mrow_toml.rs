@@ -1,5 +1,11 @@
+use std::{collections::BTreeMap, time::Duration};
+
 use crate::{
-	collapse_path, resolve_path, warn, AurHelper, Deserialize, Error, Path, PathBuf, Result, Step, StepKind, Value,
+	collapse_path,
+	privilege::Escalation,
+	prompts::{Prompt, Prompter},
+	resolve_path, warn, AurBootstrap, AurHelper, Config, Deserialize, Error, Guard, Hooks, LockPolicy, Path, PathBuf,
+	Plan, Result, RunAs, Step, StepKind, StepOptions, SyncPolicy, UnlistedPackages, Value,
 };
 
 #[derive(Debug, Clone, Deserialize)]
@@ -38,7 +44,38 @@ struct HostInclude {
 struct RawConfigTable {
 	aur_helper: Option<AurHelper>,
 	#[serde(default)]
+	sync_policy: SyncPolicy,
+	#[serde(default)]
+	lock_policy: LockPolicy,
+	#[serde(default)]
+	unlisted_packages: UnlistedPackages,
+	#[serde(default)]
+	ignored_packages: Vec<String>,
+	#[serde(default)]
+	aur_bootstrap: AurBootstrap,
+	privilege_escalation: Option<Escalation>,
+	#[serde(default)]
 	host_includes: Vec<HostInclude>,
+	age_identity: Option<String>,
+	#[serde(default)]
+	prompts: Vec<Prompt>,
+	#[serde(default)]
+	env: BTreeMap<String, String>,
+	timeout: Option<u64>,
+	retries: Option<u32>,
+	#[serde(default)]
+	hooks: RawHooks,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct RawHooks {
+	#[serde(default)]
+	pre_run: Vec<Value>,
+	#[serde(default)]
+	post_run: Vec<Value>,
+	#[serde(default)]
+	on_failure: Vec<Value>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -47,6 +84,12 @@ struct RawModuleTable {
 	includes: Includes,
 	#[serde(default)]
 	steps: Vec<Value>,
+	#[serde(default)]
+	handlers: BTreeMap<String, Vec<Value>>,
+	#[serde(default)]
+	before: Vec<Value>,
+	#[serde(default)]
+	after: Vec<Value>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -61,16 +104,51 @@ impl RawMrowFile {
 	}
 }
 
+/// Only the parts of the root `mrow secret` needs, so the rest of the config doesn't have to be valid
+#[derive(Debug, Deserialize)]
+struct RawAgeFile {
+	config: Option<RawAgeTable>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct RawAgeTable {
+	age_identity: Option<String>,
+	#[serde(default)]
+	age_recipients: Vec<String>,
+}
+
 #[derive(Debug, Clone)]
 struct ConfigTable {
 	aur_helper: Option<AurHelper>,
+	sync_policy: SyncPolicy,
+	lock_policy: LockPolicy,
+	unlisted_packages: UnlistedPackages,
+	ignored_packages: Vec<String>,
+	aur_bootstrap: AurBootstrap,
+	privilege_escalation: Option<Escalation>,
 	host_includes: Vec<HostInclude>,
+	age_identity: Option<PathBuf>,
+	env: BTreeMap<String, String>,
+	timeout: Option<Duration>,
+	retries: Option<u32>,
+	hooks: ConfigHooks,
+}
+
+#[derive(Debug, Clone)]
+struct ConfigHooks {
+	pre_run: Vec<(StepKind, StepOptions)>,
+	post_run: Vec<(StepKind, StepOptions)>,
+	on_failure: Vec<(StepKind, StepOptions)>,
 }
 
 #[derive(Debug)]
 struct ModuleTable {
 	includes: Includes,
-	steps: Vec<StepKind>,
+	steps: Vec<(StepKind, StepOptions)>,
+	handlers: BTreeMap<String, Vec<(StepKind, StepOptions)>>,
+	before: Vec<(StepKind, StepOptions)>,
+	after: Vec<(StepKind, StepOptions)>,
 }
 
 #[derive(Debug)]
@@ -85,8 +163,482 @@ struct MrowFile {
 	module: ModuleTable,
 }
 
+/// Replaces `{{ name }}` variables in every string of a step.
+fn substitute(prompter: &Prompter, value: Value) -> std::result::Result<Value, String> {
+	Ok(match value {
+		Value::String(str) => Value::String(prompter.substitute(&str)?),
+		Value::Array(values) => Value::Array(
+			values
+				.into_iter()
+				.map(|v| substitute(prompter, v))
+				.collect::<std::result::Result<_, _>>()?,
+		),
+		Value::Table(table) => Value::Table(
+			table
+				.into_iter()
+				.map(|(k, v)| Ok((k, substitute(prompter, v)?)))
+				.collect::<std::result::Result<_, String>>()?,
+		),
+		value => value,
+	})
+}
+
+fn take_string(path: &Path, table: &mut toml::Table, key: &str) -> Result<Option<String>> {
+	table
+		.remove(key)
+		.map(|v| {
+			v.as_str()
+				.map(ToString::to_string)
+				.ok_or(Error::TomlInvalidStepData(path.to_path_buf(), v))
+		})
+		.transpose()
+}
+
+fn take_strings(path: &Path, table: &mut toml::Table, key: &str) -> Result<Vec<String>> {
+	match table.remove(key) {
+		None => Ok(vec![]),
+		Some(Value::Array(values)) => values
+			.into_iter()
+			.map(|v| {
+				v.as_str()
+					.map(ToString::to_string)
+					.ok_or(Error::TomlInvalidStepData(path.to_path_buf(), v))
+			})
+			.collect(),
+		Some(v) => Err(Error::TomlInvalidStepData(path.to_path_buf(), v)),
+	}
+}
+
+fn take_env(path: &Path, table: &mut toml::Table, key: &str) -> Result<BTreeMap<String, String>> {
+	match table.remove(key) {
+		None => Ok(BTreeMap::new()),
+		Some(Value::Table(env)) => env
+			.into_iter()
+			.map(|(key, v)| match v {
+				Value::String(value) => Ok((key, value)),
+				v => Err(Error::TomlInvalidStepData(path.to_path_buf(), v)),
+			})
+			.collect(),
+		Some(v) => Err(Error::TomlInvalidStepData(path.to_path_buf(), v)),
+	}
+}
+
+fn take_count(path: &Path, table: &mut toml::Table, key: &str) -> Result<Option<u64>> {
+	table
+		.remove(key)
+		.map(|v| {
+			v.as_integer()
+				.and_then(|count| u64::try_from(count).ok())
+				.ok_or(Error::TomlInvalidStepData(path.to_path_buf(), v))
+		})
+		.transpose()
+}
+
+/// Takes the `dependencies` and `config-files` flags of remove package steps out of a step table.
+fn take_remove_flags(table: &mut toml::Table) -> (bool, bool) {
+	let mut flag = |key| table.remove(key).and_then(|v| v.as_bool()).unwrap_or_default();
+	(flag("dependencies"), flag("config-files"))
+}
+
+/// Takes the guard keys of command and script steps out of a step table.
+fn take_guard(path: &Path, table: &mut toml::Table, dir: &Path) -> Result<Guard> {
+	Ok(Guard {
+		creates: take_string(path, table, "creates")?.map(|creates| resolve_path(&creates, dir)),
+		unless: take_string(path, table, "unless")?,
+		onlyif: take_string(path, table, "onlyif")?,
+	})
+}
+
+/// Takes the keys every step kind accepts out of a step table.
+fn take_options(path: &Path, table: &mut toml::Table) -> Result<StepOptions> {
+	let as_root = table.remove("as-root").and_then(|v| v.as_bool()).unwrap_or_default();
+	let as_user = take_string(path, table, "as-user")?;
+
+	let run_as = match (as_root, as_user) {
+		(true, Some(_)) => {
+			return Err(Error::TomlInvalidStep(
+				path.to_path_buf(),
+				"'as-root' and 'as-user' can't be used together.".into(),
+			))
+		}
+		(true, None) => RunAs::Root,
+		(false, Some(user)) => RunAs::Other(user),
+		(false, None) => RunAs::User,
+	};
+
+	Ok(StepOptions {
+		run_as,
+		notify: take_strings(path, table, "notify")?,
+		timeout: take_count(path, table, "timeout")?.map(Duration::from_secs),
+		retries: take_count(path, table, "retries")?.map(|retries| u32::try_from(retries).unwrap_or(u32::MAX)),
+		ignore_errors: table
+			.remove("ignore-errors")
+			.and_then(|v| v.as_bool())
+			.unwrap_or_default(),
+	})
+}
+
+/// Parses a step written as a command, a list of commands or a table.
+fn parse_step(path: &Path, dir: &Path, prompter: &Prompter, raw: Value) -> Result<(StepKind, StepOptions)> {
+	let raw = substitute(prompter, raw).map_err(|err| Error::TomlInvalidStep(path.to_path_buf(), err))?;
+	Ok(match raw {
+		Value::String(command) => (
+			StepKind::RunCommand {
+				command,
+				env: BTreeMap::new(),
+				cwd: None,
+				guard: Guard::default(),
+			},
+			StepOptions::default(),
+		),
+		Value::Array(commands) => (
+			StepKind::RunCommands {
+				commands: commands
+					.into_iter()
+					.map(|v| {
+						v.as_str()
+							.map(ToString::to_string)
+							.ok_or(Error::TomlInvalidStepData(path.to_path_buf(), v))
+					})
+					.collect::<Result<Vec<_>>>()?,
+				env: BTreeMap::new(),
+				cwd: None,
+				guard: Guard::default(),
+			},
+			StepOptions::default(),
+		),
+		Value::Table(mut table) => {
+			let kind = table
+				.remove("kind")
+				.and_then(|v| v.as_str().map(ToString::to_string))
+				.ok_or(Error::TomlInvalidStep(path.to_path_buf(), "Missing step kind.".into()))?;
+			let options = take_options(path, &mut table)?;
+
+			let kind = match kind.as_str() {
+				"install-package" => {
+					let package = table
+						.remove("package")
+						.and_then(|v| v.as_str().map(ToString::to_string))
+						.ok_or(Error::TomlInvalidStep(
+							path.to_path_buf(),
+							"Missing 'package' key in install-package step.".into(),
+						))?;
+
+					let aur = table.remove("aur").and_then(|v| v.as_bool()).unwrap_or_default();
+
+					StepKind::InstallPackage { package, aur }
+				}
+
+				"install-packages" => {
+					let packages = table
+						.remove("packages")
+						.and_then(|v| match v {
+							Value::Array(v) => Some(v),
+							_ => None,
+						})
+						.ok_or(Error::TomlInvalidStep(
+							path.to_path_buf(),
+							"Missing 'package' key in install-package step.".into(),
+						))?
+						.into_iter()
+						.map(|v| {
+							v.as_str()
+								.map(ToString::to_string)
+								.ok_or(Error::TomlInvalidStepData(path.to_path_buf(), v))
+						})
+						.collect::<Result<Vec<_>>>()?;
+
+					let aur = table.remove("aur").and_then(|v| v.as_bool()).unwrap_or_default();
+
+					StepKind::InstallPackages { packages, aur }
+				}
+
+				"remove-package" => {
+					let package = take_string(path, &mut table, "package")?.ok_or(Error::TomlInvalidStep(
+						path.to_path_buf(),
+						"Missing 'package' key in remove-package step.".into(),
+					))?;
+					let (dependencies, config_files) = take_remove_flags(&mut table);
+
+					StepKind::RemovePackage {
+						package,
+						dependencies,
+						config_files,
+					}
+				}
+
+				"remove-packages" => {
+					if !table.contains_key("packages") {
+						return Err(Error::TomlInvalidStep(
+							path.to_path_buf(),
+							"Missing 'packages' key in remove-packages step.".into(),
+						));
+					}
+					let packages = take_strings(path, &mut table, "packages")?;
+					let (dependencies, config_files) = take_remove_flags(&mut table);
+
+					StepKind::RemovePackages {
+						packages,
+						dependencies,
+						config_files,
+					}
+				}
+
+				"local-package" => {
+					let package_path = take_string(path, &mut table, "path")?.ok_or(Error::TomlInvalidStep(
+						path.to_path_buf(),
+						"Missing 'path' key in local-package step.".into(),
+					))?;
+
+					let package_path = resolve_path(&package_path, dir);
+					if !package_path.join("PKGBUILD").is_file() {
+						return Err(Error::TomlInvalidStep(
+							path.to_path_buf(),
+							format!("No PKGBUILD in '{}'.", package_path.to_string_lossy()),
+						));
+					}
+
+					StepKind::LocalPackage { path: package_path }
+				}
+
+				"pacman-repo" => {
+					let name = take_string(path, &mut table, "name")?.ok_or(Error::TomlInvalidStep(
+						path.to_path_buf(),
+						"Missing 'name' key in pacman-repo step.".into(),
+					))?;
+					let servers = take_strings(path, &mut table, "servers")?;
+					let include = take_string(path, &mut table, "include")?;
+					if servers.is_empty() && include.is_none() {
+						return Err(Error::TomlInvalidStep(
+							path.to_path_buf(),
+							"A pacman-repo step needs 'servers' or 'include'.".into(),
+						));
+					}
+
+					StepKind::PacmanRepo {
+						name,
+						servers,
+						include,
+						sig_level: take_string(path, &mut table, "sig-level")?,
+						key: take_string(path, &mut table, "key")?,
+						keyserver: take_string(path, &mut table, "keyserver")?,
+					}
+				}
+
+				"copy-file" => {
+					let from_path = table
+						.remove("from")
+						.map(|v| {
+							v.as_str()
+								.map(ToString::to_string)
+								.ok_or(Error::TomlInvalidStepData(path.to_path_buf(), v))
+						})
+						.ok_or(Error::TomlInvalidStep(
+							path.to_path_buf(),
+							"Missing 'from' key in copy-file step.".into(),
+						))??;
+
+					let to_path = table
+						.remove("to")
+						.map(|v| {
+							v.as_str()
+								.map(ToString::to_string)
+								.ok_or(Error::TomlInvalidStepData(path.to_path_buf(), v))
+						})
+						.ok_or(Error::TomlInvalidStep(
+							path.to_path_buf(),
+							"Missing 'to' key in copy-file step.".into(),
+						))??;
+
+					StepKind::CopyFile {
+						from: resolve_path(&from_path, dir),
+						to: resolve_path(&to_path, dir),
+					}
+				}
+
+				"secret-file" => {
+					let from_path = table
+						.remove("from")
+						.map(|v| {
+							v.as_str()
+								.map(ToString::to_string)
+								.ok_or(Error::TomlInvalidStepData(path.to_path_buf(), v))
+						})
+						.ok_or(Error::TomlInvalidStep(
+							path.to_path_buf(),
+							"Missing 'from' key in secret-file step.".into(),
+						))??;
+
+					let to_path = table
+						.remove("to")
+						.map(|v| {
+							v.as_str()
+								.map(ToString::to_string)
+								.ok_or(Error::TomlInvalidStepData(path.to_path_buf(), v))
+						})
+						.ok_or(Error::TomlInvalidStep(
+							path.to_path_buf(),
+							"Missing 'to' key in secret-file step.".into(),
+						))??;
+
+					StepKind::SecretFile {
+						from: resolve_path(&from_path, dir),
+						to: resolve_path(&to_path, dir),
+					}
+				}
+
+				"symlink" => {
+					let from_path = table
+						.remove("from")
+						.map(|v| {
+							v.as_str()
+								.map(ToString::to_string)
+								.ok_or(Error::TomlInvalidStepData(path.to_path_buf(), v))
+						})
+						.ok_or(Error::TomlInvalidStep(
+							path.to_path_buf(),
+							"Missing 'from' key in write-file step.".into(),
+						))??;
+
+					let to_path = table
+						.remove("to")
+						.map(|v| {
+							v.as_str()
+								.map(ToString::to_string)
+								.ok_or(Error::TomlInvalidStepData(path.to_path_buf(), v))
+						})
+						.ok_or(Error::TomlInvalidStep(
+							path.to_path_buf(),
+							"Missing 'to' key in write-file step.".into(),
+						))??;
+
+					let delete_existing = table
+						.remove("delete-existing")
+						.and_then(|v| v.as_bool())
+						.unwrap_or_default();
+
+					StepKind::Symlink {
+						from: resolve_path(&from_path, dir),
+						to: resolve_path(&to_path, dir),
+						delete_existing,
+					}
+				}
+
+				"run-command" => {
+					let command = table
+						.remove("command")
+						.map(|v| {
+							v.as_str()
+								.map(ToString::to_string)
+								.ok_or(Error::TomlInvalidStepData(path.to_path_buf(), v))
+						})
+						.ok_or(Error::TomlInvalidStep(
+							path.to_path_buf(),
+							"Missing 'command' key in run-command step.".into(),
+						))??;
+
+					StepKind::RunCommand {
+						command,
+						env: take_env(path, &mut table, "env")?,
+						cwd: take_string(path, &mut table, "cwd")?.map(|cwd| resolve_path(&cwd, dir)),
+						guard: take_guard(path, &mut table, dir)?,
+					}
+				}
+
+				"run-commands" => {
+					let commands = table
+						.remove("commands")
+						.and_then(|v| match v {
+							Value::Array(v) => Some(v),
+							_ => None,
+						})
+						.ok_or(Error::TomlInvalidStep(
+							path.to_path_buf(),
+							"Missing 'commands' key in run-commands step.".into(),
+						))?
+						.into_iter()
+						.map(|v| {
+							v.as_str()
+								.map(ToString::to_string)
+								.ok_or(Error::TomlInvalidStepData(path.to_path_buf(), v))
+						})
+						.collect::<Result<Vec<_>>>()?;
+
+					StepKind::RunCommands {
+						commands,
+						env: take_env(path, &mut table, "env")?,
+						cwd: take_string(path, &mut table, "cwd")?.map(|cwd| resolve_path(&cwd, dir)),
+						guard: take_guard(path, &mut table, dir)?,
+					}
+				}
+
+				"run-script" => {
+					let script_path = table
+						.remove("path")
+						.map(|v| {
+							v.as_str()
+								.map(ToString::to_string)
+								.ok_or(Error::TomlInvalidStepData(path.to_path_buf(), v))
+						})
+						.ok_or(Error::TomlInvalidStep(
+							path.to_path_buf(),
+							"Missing 'path' key in run-script step.".into(),
+						))??;
+
+					let script_path = resolve_path(&script_path, dir);
+					if !script_path.is_file() {
+						return Err(Error::TomlInvalidStep(
+							path.to_path_buf(),
+							format!("Script '{}' doesn't exist.", script_path.to_string_lossy()),
+						));
+					}
+
+					StepKind::RunScript {
+						path: script_path,
+						interpreter: take_string(path, &mut table, "interpreter")?,
+						args: take_strings(path, &mut table, "args")?,
+						env: take_env(path, &mut table, "env")?,
+						cwd: take_string(path, &mut table, "cwd")?.map(|cwd| resolve_path(&cwd, dir)),
+						guard: take_guard(path, &mut table, dir)?,
+					}
+				}
+				"inline-script" => StepKind::InlineScript {
+					body: take_string(path, &mut table, "script")?.ok_or(Error::TomlInvalidStep(
+						path.to_path_buf(),
+						"Missing 'script' key in inline-script step.".into(),
+					))?,
+					interpreter: take_string(path, &mut table, "interpreter")?,
+					env: take_env(path, &mut table, "env")?,
+					cwd: take_string(path, &mut table, "cwd")?.map(|cwd| resolve_path(&cwd, dir)),
+					guard: take_guard(path, &mut table, dir)?,
+				},
+
+				_ => {
+					return Err(Error::TomlInvalidStep(
+						path.to_path_buf(),
+						format!("Invalid step kind: {kind}"),
+					))
+				}
+			};
+
+			(kind, options)
+		}
+
+		value => return Err(Error::TomlInvalidStepData(path.to_path_buf(), value)),
+	})
+}
+
 impl MrowFile {
-	fn new(root_dir: &Path, path: &Path) -> Result<MrowFile> {
+	fn step(&self, (kind, options): (StepKind, StepOptions)) -> Step {
+		Step {
+			owner: self.path.clone(),
+			relative_path_str: self.relative_path_str.clone(),
+			kind,
+			options,
+		}
+	}
+
+	fn new(root_dir: &Path, path: &Path, prompter: &Prompter) -> Result<MrowFile> {
 		let relative_path = collapse_path(root_dir, path);
 
 		let dir = path
@@ -98,183 +650,69 @@ impl MrowFile {
 		let path = path.canonicalize()?;
 
 		let raw = RawMrowFile::new(path.clone())?;
-		let config = raw.config.filter(|_| relative_path == PathBuf::from("mrow.toml")).map(
-			|RawConfigTable {
-			     aur_helper,
-			     host_includes,
-			 }| ConfigTable {
+		let parse_steps = |raw: Vec<Value>| -> Result<Vec<(StepKind, StepOptions)>> {
+			raw.into_iter()
+				.map(|raw| parse_step(&path, &dir, prompter, raw))
+				.collect()
+		};
+
+		let config = match raw.config.filter(|_| relative_path == PathBuf::from("mrow.toml")) {
+			Some(RawConfigTable {
 				aur_helper,
+				sync_policy,
+				lock_policy,
+				unlisted_packages,
+				ignored_packages,
+				aur_bootstrap,
+				privilege_escalation,
 				host_includes,
-			},
-		);
+				age_identity,
+				prompts: _,
+				env,
+				timeout,
+				retries,
+				hooks,
+			}) => Some(ConfigTable {
+				aur_helper,
+				sync_policy,
+				lock_policy,
+				unlisted_packages,
+				ignored_packages,
+				aur_bootstrap: AurBootstrap {
+					build_dir: aur_bootstrap
+						.build_dir
+						.map(|p| resolve_path(&p.to_string_lossy(), &dir)),
+					..aur_bootstrap
+				},
+				privilege_escalation,
+				host_includes,
+				age_identity: age_identity.map(|p| resolve_path(&p, &dir)),
+				env,
+				timeout: timeout.map(Duration::from_secs),
+				retries,
+				hooks: ConfigHooks {
+					pre_run: parse_steps(hooks.pre_run)?,
+					post_run: parse_steps(hooks.post_run)?,
+					on_failure: parse_steps(hooks.on_failure)?,
+				},
+			}),
+			None => None,
+		};
 
 		let module: ModuleTable = {
-			let mut steps = Vec::with_capacity(raw.module.steps.len());
-
-			for raw in raw.module.steps {
-				let step = match raw {
-					Value::String(command) => StepKind::RunCommand { command },
-					Value::Array(commands) => StepKind::RunCommands {
-						commands: commands
-							.into_iter()
-							.map(|v| {
-								v.as_str()
-									.map(ToString::to_string)
-									.ok_or(Error::TomlInvalidStepData(path.clone(), v))
-							})
-							.collect::<Result<Vec<_>>>()?,
-					},
-					Value::Table(mut table) => {
-						let kind = table
-							.remove("kind")
-							.and_then(|v| v.as_str().map(ToString::to_string))
-							.ok_or(Error::TomlInvalidStep(path.clone(), "Missing step kind.".into()))?;
-
-						match kind.as_str() {
-							"install-package" => {
-								let package = table
-									.remove("package")
-									.and_then(|v| v.as_str().map(ToString::to_string))
-									.ok_or(Error::TomlInvalidStep(
-										path.clone(),
-										"Missing 'package' key in install-package step.".into(),
-									))?;
-
-								let aur = table.remove("aur").and_then(|v| v.as_bool()).unwrap_or_default();
-
-								StepKind::InstallPackage { package, aur }
-							}
-
-							"install-packages" => {
-								let packages = table
-									.remove("packages")
-									.and_then(|v| match v {
-										Value::Array(v) => Some(v),
-										_ => None,
-									})
-									.ok_or(Error::TomlInvalidStep(
-										path.clone(),
-										"Missing 'package' key in install-package step.".into(),
-									))?
-									.into_iter()
-									.map(|v| {
-										v.as_str()
-											.map(ToString::to_string)
-											.ok_or(Error::TomlInvalidStepData(path.clone(), v))
-									})
-									.collect::<Result<Vec<_>>>()?;
-
-								let aur = table.remove("aur").and_then(|v| v.as_bool()).unwrap_or_default();
-
-								StepKind::InstallPackages { packages, aur }
-							}
-
-							"copy-file" => {
-								let from_path = table
-									.remove("from")
-									.map(|v| {
-										v.as_str()
-											.map(ToString::to_string)
-											.ok_or(Error::TomlInvalidStepData(path.clone(), v))
-									})
-									.ok_or(Error::TomlInvalidStep(
-										path.clone(),
-										"Missing 'from' key in copy-file step.".into(),
-									))??;
-
-								let to_path = table
-									.remove("to")
-									.map(|v| {
-										v.as_str()
-											.map(ToString::to_string)
-											.ok_or(Error::TomlInvalidStepData(path.clone(), v))
-									})
-									.ok_or(Error::TomlInvalidStep(
-										path.clone(),
-										"Missing 'to' key in copy-file step.".into(),
-									))??;
-
-								let as_root = table.remove("as-root").and_then(|v| v.as_bool()).unwrap_or_default();
-
-								StepKind::CopyFile {
-									from: resolve_path(&from_path, &dir),
-									to: resolve_path(&to_path, &dir),
-									as_root,
-								}
-							}
-
-							"symlink" => {
-								let from_path = table
-									.remove("from")
-									.map(|v| {
-										v.as_str()
-											.map(ToString::to_string)
-											.ok_or(Error::TomlInvalidStepData(path.clone(), v))
-									})
-									.ok_or(Error::TomlInvalidStep(
-										path.clone(),
-										"Missing 'from' key in write-file step.".into(),
-									))??;
-
-								let to_path = table
-									.remove("to")
-									.map(|v| {
-										v.as_str()
-											.map(ToString::to_string)
-											.ok_or(Error::TomlInvalidStepData(path.clone(), v))
-									})
-									.ok_or(Error::TomlInvalidStep(
-										path.clone(),
-										"Missing 'to' key in write-file step.".into(),
-									))??;
-
-								let delete_existing = table
-									.remove("delete-existing")
-									.and_then(|v| v.as_bool())
-									.unwrap_or_default();
-
-								StepKind::Symlink {
-									from: resolve_path(&from_path, &dir),
-									to: resolve_path(&to_path, &dir),
-									delete_existing,
-								}
-							}
-
-							"run-script" => {
-								let script_path = table
-									.remove("path")
-									.map(|v| {
-										v.as_str()
-											.map(ToString::to_string)
-											.ok_or(Error::TomlInvalidStepData(path.clone(), v))
-									})
-									.ok_or(Error::TomlInvalidStep(
-										path.clone(),
-										"Missing 'from' key in write-file step.".into(),
-									))??;
-
-								StepKind::RunScript {
-									path: resolve_path(&script_path, &dir),
-								}
-							}
-
-							_ => {
-								return Err(Error::TomlInvalidStep(
-									path.clone(),
-									format!("Invalid step kind: {kind}"),
-								))
-							}
-						}
-					}
+			let steps = parse_steps(raw.module.steps)?;
 
-					value => return Err(Error::TomlInvalidStepData(path.clone(), value)),
-				};
-				steps.push(step);
+			let mut handlers = BTreeMap::new();
+			for (name, raw) in raw.module.handlers {
+				handlers.insert(name, parse_steps(raw)?);
 			}
 
 			ModuleTable {
 				includes: raw.module.includes,
 				steps,
+				handlers,
+				before: parse_steps(raw.module.before)?,
+				after: parse_steps(raw.module.after)?,
 			}
 		};
 
@@ -288,7 +726,12 @@ impl MrowFile {
 	}
 }
 
-fn gather_includes(root_dir: &Path, file: &MrowFile, includes: &Includes) -> Result<Vec<MrowFile>> {
+fn gather_includes(
+	root_dir: &Path,
+	file: &MrowFile,
+	includes: &Includes,
+	prompter: &Prompter,
+) -> Result<Vec<MrowFile>> {
 	match &includes {
 		Includes::None => vec![],
 		Includes::One(include) => vec![PathBuf::from(include)],
@@ -298,7 +741,7 @@ fn gather_includes(root_dir: &Path, file: &MrowFile, includes: &Includes) -> Res
 	.map(|path| file.dir.join(path))
 	.map(|path| {
 		if path.exists() {
-			MrowFile::new(root_dir, &path)
+			MrowFile::new(root_dir, &path, prompter)
 		} else {
 			Err(Error::TomlImportNotFound(file.path.clone(), path))
 		}
@@ -306,17 +749,24 @@ fn gather_includes(root_dir: &Path, file: &MrowFile, includes: &Includes) -> Res
 	.collect()
 }
 
-fn get_all_steps(root_dir: &Path, base: &MrowFile, host_includes: Option<Includes>) -> Result<Vec<Step>> {
-	let mut includes = match host_includes.map(|i| gather_includes(root_dir, base, &i)) {
+fn get_all_steps(
+	root_dir: &Path,
+	base: &MrowFile,
+	host_includes: Option<Includes>,
+	prompter: &Prompter,
+) -> Result<Plan> {
+	let mut includes = match host_includes.map(|i| gather_includes(root_dir, base, &i, prompter)) {
 		Some(Ok(includes)) => includes,
 		Some(Err(err)) => Err(err)?,
 		None => vec![],
 	};
-	includes.extend(gather_includes(root_dir, base, &base.module.includes)?);
+	includes.extend(gather_includes(root_dir, base, &base.module.includes, prompter)?);
 
 	includes
 		.iter()
-		.filter(|include| include.module.steps.is_empty() && include.module.includes.empty())
+		.filter(|include| {
+			include.module.steps.is_empty() && include.module.handlers.is_empty() && include.module.includes.empty()
+		})
 		.for_each(|include| {
 			warn!(
 				"'{}' is a no-op since it contains no steps or includes.",
@@ -324,21 +774,34 @@ fn get_all_steps(root_dir: &Path, base: &MrowFile, host_includes: Option<Include
 			);
 		});
 
-	let mut steps = base
-		.module
-		.steps
-		.iter()
-		.cloned()
-		.map(|kind| Step {
-			owner: base.path.clone(),
-			relative_path_str: base.relative_path_str.clone(),
-			kind,
-		})
-		.collect::<Vec<_>>();
+	let mut plan = Plan {
+		steps: base.module.steps.iter().cloned().map(|step| base.step(step)).collect(),
+		..Default::default()
+	};
+	if !base.module.before.is_empty() {
+		let steps = base.module.before.iter().cloned().map(|step| base.step(step)).collect();
+		plan.before.insert(base.path.clone(), steps);
+	}
+	if !base.module.after.is_empty() {
+		let steps = base.module.after.iter().cloned().map(|step| base.step(step)).collect();
+		plan.after.insert(base.path.clone(), steps);
+	}
+	for (name, steps) in &base.module.handlers {
+		plan.add_handler(
+			name.clone(),
+			steps.iter().cloned().map(|step| base.step(step)).collect(),
+		)?;
+	}
 	for include in includes {
-		steps.extend(get_all_steps(root_dir, &include, None)?);
+		let include = get_all_steps(root_dir, &include, None, prompter)?;
+		plan.steps.extend(include.steps);
+		plan.before.extend(include.before);
+		plan.after.extend(include.after);
+		for (name, steps) in include.handlers {
+			plan.add_handler(name, steps)?;
+		}
 	}
-	Ok(steps)
+	Ok(plan)
 }
 
 pub fn process(
@@ -346,12 +809,42 @@ pub fn process(
 	root_file: &Path,
 	exec_single: Option<PathBuf>,
 	hostname: &str,
-) -> Result<(Vec<Step>, Option<AurHelper>)> {
-	let root = MrowFile::new(base_dir, root_file)?;
-	let aur_helper = root.config.as_ref().and_then(|c| c.aur_helper);
+	prompter: &mut Prompter,
+) -> Result<(Plan, Config)> {
+	let prompts = RawMrowFile::new(root_file.canonicalize()?)?
+		.config
+		.map(|c| c.prompts)
+		.unwrap_or_default();
+	for prompt in &prompts {
+		prompter.ask(prompt)?;
+	}
 
-	let all_steps = match exec_single {
-		Some(exec_single) => get_all_steps(&root.dir, &MrowFile::new(base_dir, &exec_single)?, None)?,
+	let root = MrowFile::new(base_dir, root_file, prompter)?;
+	let config = root
+		.config
+		.as_ref()
+		.map(|c| Config {
+			aur_helper: c.aur_helper,
+			sync_policy: c.sync_policy,
+			lock_policy: c.lock_policy,
+			unlisted_packages: c.unlisted_packages,
+			ignored_packages: c.ignored_packages.clone(),
+			aur_bootstrap: c.aur_bootstrap.clone(),
+			escalation: c.privilege_escalation,
+			age_identity: c.age_identity.clone(),
+			env: c.env.clone(),
+			timeout: c.timeout,
+			retries: c.retries,
+		})
+		.unwrap_or_default();
+
+	let mut plan = match exec_single {
+		Some(exec_single) => get_all_steps(
+			&root.dir,
+			&MrowFile::new(base_dir, &exec_single, prompter)?,
+			None,
+			prompter,
+		)?,
 		None => get_all_steps(
 			&root.dir,
 			&root,
@@ -360,8 +853,31 @@ pub fn process(
 				.map(|c| c.host_includes.clone())
 				.and_then(|i| i.into_iter().find(|i| i.hostname == hostname))
 				.map(|i| i.includes),
+			prompter,
 		)?,
 	};
 
-	Ok((all_steps, aur_helper))
+	// Hooks come from the root config, so they also run when only a single module is executed
+	if let Some(hooks) = root.config.as_ref().map(|c| &c.hooks) {
+		let steps = |steps: &[(StepKind, StepOptions)]| steps.iter().cloned().map(|step| root.step(step)).collect();
+		plan.hooks = Hooks {
+			pre_run: steps(&hooks.pre_run),
+			post_run: steps(&hooks.post_run),
+			on_failure: steps(&hooks.on_failure),
+		};
+	}
+
+	Ok((plan, config))
+}
+
+/// Reads only `age-identity` and `age-recipients` from the root for `mrow secret`, without asking prompts or resolving
+/// steps.
+pub fn age_config(base_dir: &Path, root_file: &Path) -> Result<(Option<PathBuf>, Vec<String>)> {
+	let path = root_file.canonicalize()?;
+	let raw: RawAgeFile = toml::from_str(&std::fs::read_to_string(&path)?).map_err(|err| Error::Toml(path, err))?;
+	let config = raw.config.unwrap_or_default();
+	Ok((
+		config.age_identity.map(|p| resolve_path(&p, base_dir)),
+		config.age_recipients,
+	))
 }
@@ -0,0 +1,331 @@
+use std::{
+	collections::{BTreeMap, BTreeSet},
+	process::Command,
+	time::{Duration, SystemTime},
+};
+
+use crate::{debug, Error, Path, PathBuf, Result};
+
+/// Where pacman keeps a directory with a `desc` file for every installed package.
+const LOCAL_DB: &str = "/var/lib/pacman/local";
+/// Where pacman keeps the `<repo>.db` archives it downloads when syncing.
+const SYNC_DB: &str = "/var/lib/pacman/sync";
+/// pacman's config, which has a section for every repo.
+pub const PACMAN_CONF: &str = "/etc/pacman.conf";
+const AUR_PACKAGES_URL: &str = "https://aur.archlinux.org/packages.gz";
+/// How old the cached list of AUR packages may get before it's downloaded again.
+const AUR_PACKAGES_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InstallReason {
+	Explicit,
+	/// Installed as a dependency of another package
+	Dependency,
+}
+
+#[derive(Debug, Clone)]
+pub struct LocalPackage {
+	pub version: String,
+	pub reason: InstallReason,
+	/// Other names the package can be installed as, without versions
+	pub provides: Vec<String>,
+	pub groups: Vec<String>,
+}
+
+/// The installed packages, read straight from pacman's local database instead of spawning pacman for every check.
+#[derive(Debug, Default)]
+pub struct LocalDb {
+	packages: BTreeMap<String, LocalPackage>,
+	/// Names provided by installed packages
+	provided: BTreeSet<String>,
+	/// Groups all members of which are installed, see `complete_groups`
+	complete_groups: BTreeSet<String>,
+}
+
+impl LocalDb {
+	pub fn load() -> Result<Self> {
+		Self::load_from(Path::new(LOCAL_DB))
+	}
+
+	pub fn load_from(dir: &Path) -> Result<Self> {
+		let mut packages = BTreeMap::new();
+		let mut provided = BTreeSet::new();
+		for entry in std::fs::read_dir(dir)? {
+			let desc = entry?.path().join("desc");
+			// The database also contains an ALPM_DB_VERSION file, which has no desc
+			if !desc.is_file() {
+				continue;
+			}
+
+			if let Some((name, package)) = parse_desc(&std::fs::read_to_string(desc)?) {
+				provided.extend(package.provides.iter().cloned());
+				packages.insert(name, package);
+			}
+		}
+		Ok(Self {
+			packages,
+			provided,
+			complete_groups: BTreeSet::new(),
+		})
+	}
+
+	pub fn get(&self, name: &str) -> Option<&LocalPackage> {
+		self.packages.get(name)
+	}
+
+	/// Whether a package or a package providing `name` is installed, or every package in the group `name`.
+	pub fn is_installed(&self, name: &str) -> bool {
+		self.packages.contains_key(name) || self.provided.contains(name) || self.complete_groups.contains(name)
+	}
+
+	/// Whether any installed package is in the group `name`.
+	pub fn has_group(&self, name: &str) -> bool {
+		self.packages
+			.values()
+			.any(|package| package.groups.iter().any(|group| group == name))
+	}
+
+	/// Marks the groups all members of which, according to the sync databases, are installed. Until this is called no
+	/// group counts as installed, so groups are always passed to pacman, which installs their missing members.
+	pub fn complete_groups(&mut self, sync: &SyncDb) {
+		self.complete_groups = sync
+			.groups
+			.iter()
+			.filter(|(_, members)| members.iter().all(|member| self.packages.contains_key(member)))
+			.map(|(group, _)| group.clone())
+			.collect();
+	}
+
+	pub fn iter(&self) -> impl Iterator<Item = (&String, &LocalPackage)> {
+		self.packages.iter()
+	}
+}
+
+/// Strips the version from a provided name, e.g. 'sh=5.2'.
+pub fn without_version(name: &str) -> &str {
+	name.split(['=', '<', '>']).next().unwrap_or(name)
+}
+
+/// The fields of `desc` files, which are made of `%FIELD%` headers each followed by their values and an empty line.
+/// Several files may be concatenated.
+fn desc_fields(desc: &str) -> impl Iterator<Item = (&str, Vec<&str>)> {
+	let mut lines = desc.lines();
+	std::iter::from_fn(move || {
+		let header = lines.by_ref().find(|line| line.starts_with('%'))?;
+		let values = lines.by_ref().take_while(|line| !line.is_empty()).collect();
+		Some((header, values))
+	})
+}
+
+fn parse_desc(desc: &str) -> Option<(String, LocalPackage)> {
+	let mut name = None;
+	let mut version = None;
+	let mut reason = InstallReason::Explicit;
+	let mut provides = vec![];
+	let mut groups = vec![];
+
+	for (header, values) in desc_fields(desc) {
+		match header {
+			"%NAME%" => name = values.first().copied(),
+			"%VERSION%" => version = values.first().copied(),
+			"%REASON%" if values.first() == Some(&"1") => reason = InstallReason::Dependency,
+			"%PROVIDES%" => {
+				provides = values
+					.into_iter()
+					.map(|value| without_version(value).to_string())
+					.collect();
+			}
+			"%GROUPS%" => groups = values.into_iter().map(str::to_string).collect(),
+			_ => {}
+		}
+	}
+
+	Some((
+		name?.to_string(),
+		LocalPackage {
+			version: version?.to_string(),
+			reason,
+			provides,
+			groups,
+		},
+	))
+}
+
+/// The packages in the sync databases, as of the last time they were synced.
+#[derive(Debug, Default)]
+pub struct SyncDb {
+	/// Package names and their versions
+	packages: BTreeMap<String, String>,
+	/// Names that can be installed without being a package, i.e. groups and provided names
+	other: BTreeSet<String>,
+	/// Groups and the packages in them
+	groups: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl SyncDb {
+	/// Reads every `<repo>.db` with bsdtar, which pacman depends on through libarchive.
+	pub fn load() -> Result<Self> {
+		let mut db = Self::default();
+		for entry in std::fs::read_dir(SYNC_DB)? {
+			let path = entry?.path();
+			if !path.extension().is_some_and(|ext| ext == "db") {
+				continue;
+			}
+
+			let output = Command::new("bsdtar").arg("-xOf").arg(&path).arg("*/desc").output()?;
+			if !output.status.success() {
+				return Err(Error::PackageList(
+					path.to_string_lossy().into_owned(),
+					String::from_utf8_lossy(&output.stderr).trim().to_string(),
+				));
+			}
+
+			let descs = String::from_utf8_lossy(&output.stdout);
+			// %NAME% comes first in every desc
+			let mut name = None;
+			for (header, values) in desc_fields(&descs) {
+				match header {
+					"%NAME%" => name = values.first().map(|name| (*name).to_string()),
+					"%VERSION%" => {
+						if let (Some(name), Some(version)) = (name.clone(), values.first()) {
+							db.packages.insert(name, (*version).to_string());
+						}
+					}
+					"%GROUPS%" => {
+						for group in values {
+							db.other.insert(group.to_string());
+							if let Some(ref name) = name {
+								db.groups.entry(group.to_string()).or_default().insert(name.clone());
+							}
+						}
+					}
+					"%PROVIDES%" => db
+						.other
+						.extend(values.into_iter().map(|value| without_version(value).to_string())),
+					_ => {}
+				}
+			}
+		}
+		Ok(db)
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.packages.is_empty()
+	}
+
+	pub fn contains(&self, name: &str) -> bool {
+		self.packages.contains_key(name) || self.other.contains(name)
+	}
+
+	pub fn packages(&self) -> impl Iterator<Item = &String> {
+		self.packages.keys()
+	}
+
+	pub fn version(&self, name: &str) -> Option<&str> {
+		self.packages.get(name).map(String::as_str)
+	}
+}
+
+/// Whether the database of a repo was downloaded yet.
+pub fn has_sync_db(repo: &str) -> bool {
+	Path::new(SYNC_DB).join(format!("{repo}.db")).is_file()
+}
+
+/// Replaces the `[name]` section of a pacman.conf with `section`, or appends it if there's none. Empty lines and
+/// comments at the end of the old section are kept, they belong to whatever comes next.
+pub fn set_repo(conf: &str, name: &str, section: &str) -> String {
+	let lines = conf.lines().collect::<Vec<_>>();
+	let header = format!("[{name}]");
+	let Some(start) = lines.iter().position(|line| line.trim() == header) else {
+		return format!("{}\n\n{section}", conf.trim_end());
+	};
+
+	let mut end = lines[start + 1..]
+		.iter()
+		.position(|line| line.trim_start().starts_with('['))
+		.map_or(lines.len(), |i| start + 1 + i);
+	while end > start + 1 && (lines[end - 1].trim().is_empty() || lines[end - 1].trim_start().starts_with('#')) {
+		end -= 1;
+	}
+
+	let mut updated = lines[..start].to_vec();
+	updated.extend(section.lines());
+	updated.extend(&lines[end..]);
+	updated.join("\n") + "\n"
+}
+
+/// Loads the names of all AUR packages, downloading the list again if the cached one is older than a day.
+pub fn aur_packages() -> Result<BTreeSet<String>> {
+	let cache = dirs::cache_dir()
+		.unwrap_or_else(std::env::temp_dir)
+		.join("mrow")
+		.join("aur-packages.gz");
+	let fresh = std::fs::metadata(&cache)
+		.and_then(|metadata| metadata.modified())
+		.is_ok_and(|modified| {
+			SystemTime::now()
+				.duration_since(modified)
+				.is_ok_and(|age| age < AUR_PACKAGES_MAX_AGE)
+		});
+
+	if !fresh {
+		debug!("Downloading the list of AUR packages to '{}'", cache.to_string_lossy());
+		if let Some(parent) = cache.parent() {
+			std::fs::create_dir_all(parent)?;
+		}
+		let partial = PathBuf::from(format!("{}.part", cache.to_string_lossy()));
+		let output = Command::new("curl")
+			.args(["-fsSL", "-o"])
+			.arg(&partial)
+			.arg(AUR_PACKAGES_URL)
+			.output()?;
+		if !output.status.success() {
+			return Err(Error::PackageList(
+				AUR_PACKAGES_URL.into(),
+				String::from_utf8_lossy(&output.stderr).trim().to_string(),
+			));
+		}
+		std::fs::rename(&partial, &cache)?;
+	}
+
+	let output = Command::new("gzip").arg("-dc").arg(&cache).output()?;
+	if !output.status.success() {
+		return Err(Error::PackageList(
+			cache.to_string_lossy().into_owned(),
+			String::from_utf8_lossy(&output.stderr).trim().to_string(),
+		));
+	}
+	Ok(String::from_utf8_lossy(&output.stdout)
+		.lines()
+		.filter(|line| !line.is_empty() && !line.starts_with('#'))
+		.map(str::to_string)
+		.collect())
+}
+
+/// The names closest to `name`, for "did you mean" suggestions.
+pub fn suggestions<'a>(name: &str, candidates: impl IntoIterator<Item = &'a String>) -> Vec<&'a str> {
+	let max_distance = (name.chars().count() / 3).max(2);
+	let mut close = candidates
+		.into_iter()
+		.filter_map(|candidate| {
+			let distance = levenshtein(name, candidate);
+			(distance <= max_distance).then_some((distance, candidate.as_str()))
+		})
+		.collect::<Vec<_>>();
+	close.sort_unstable();
+	close.into_iter().take(3).map(|(_, candidate)| candidate).collect()
+}
+
+fn levenshtein(a: &str, b: &str) -> usize {
+	let b = b.chars().collect::<Vec<_>>();
+	let mut previous = (0..=b.len()).collect::<Vec<_>>();
+	for (i, a) in a.chars().enumerate() {
+		let mut current = vec![i + 1; b.len() + 1];
+		for (j, b) in b.iter().enumerate() {
+			let substitution = previous[j] + usize::from(a != *b);
+			current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+		}
+		previous = current;
+	}
+	previous[b.len()]
+}
@@ -0,0 +1,288 @@
+use std::{
+	collections::{BTreeMap, BTreeSet},
+	os::unix::fs::MetadataExt,
+	process::Command,
+};
+
+use mlua::FromLua;
+
+use crate::{info, Deserialize, Error, LazyLock, Lua, PathBuf, Result};
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Escalation {
+	Sudo,
+	Doas,
+	Run0,
+	None,
+}
+
+impl<'lua> FromLua<'lua> for Escalation {
+	fn from_lua(value: mlua::Value<'lua>, _lua: &'lua Lua) -> mlua::Result<Self> {
+		let Some(str) = value.as_str() else {
+			return Err(mlua::Error::FromLuaConversionError {
+				from: value.type_name(),
+				to: "Escalation",
+				message: None,
+			});
+		};
+
+		Ok(match str {
+			"sudo" => Escalation::Sudo,
+			"doas" => Escalation::Doas,
+			"run0" => Escalation::Run0,
+			"none" => Escalation::None,
+			v => {
+				return Err(mlua::Error::FromLuaConversionError {
+					from: value.type_name(),
+					to: "Escalation",
+					message: Some(format!("Expected 'sudo', 'doas', 'run0' or 'none'. Got '{v}'")),
+				})
+			}
+		})
+	}
+}
+
+impl Escalation {
+	pub fn name(self) -> &'static str {
+		match self {
+			Escalation::Sudo => "sudo",
+			Escalation::Doas => "doas",
+			Escalation::Run0 => "run0",
+			Escalation::None => "none",
+		}
+	}
+}
+
+/// Who a step runs as.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum RunAs {
+	/// The user running mrow, or the user that started it through sudo or doas.
+	#[default]
+	User,
+	Root,
+	Other(String),
+}
+
+impl RunAs {
+	/// Appended to step log lines, empty for the current user.
+	pub fn suffix(&self) -> String {
+		match self {
+			RunAs::User => String::new(),
+			RunAs::Root => " as root".into(),
+			RunAs::Other(user) => format!(" as {user}"),
+		}
+	}
+}
+
+fn is_root() -> bool {
+	// /proc/self is owned by the effective user of the process
+	std::fs::metadata("/proc/self").is_ok_and(|m| m.uid() == 0)
+}
+
+/// The user that started mrow through sudo or doas, if mrow is running as root.
+fn invoking_user() -> Option<String> {
+	static INVOKING_USER: LazyLock<Option<String>> = LazyLock::new(|| {
+		if !is_root() {
+			return None;
+		}
+
+		["SUDO_USER", "DOAS_USER"]
+			.into_iter()
+			.find_map(|var| std::env::var(var).ok())
+			.filter(|user| !user.is_empty() && user != "root")
+	});
+
+	INVOKING_USER.clone()
+}
+
+fn passwd_home(user: &str) -> Option<PathBuf> {
+	std::fs::read_to_string("/etc/passwd").ok()?.lines().find_map(|line| {
+		let fields = line.split(':').collect::<Vec<_>>();
+		(fields.first() == Some(&user)).then(|| fields.get(5).map(PathBuf::from))?
+	})
+}
+
+/// The home directory `~` refers to. When mrow was started through sudo this is the invoking user's home, not root's.
+pub fn home_dir() -> Option<PathBuf> {
+	static HOME_DIR: LazyLock<Option<PathBuf>> = LazyLock::new(|| {
+		invoking_user()
+			.and_then(|user| passwd_home(&user))
+			.or_else(dirs::home_dir)
+	});
+
+	HOME_DIR.clone()
+}
+
+/// How mrow runs things as root and as the user.
+#[derive(Debug)]
+pub struct Privilege {
+	pub escalation: Escalation,
+	/// Set when mrow itself runs as root on behalf of a user, user-level steps are run as them.
+	invoking_user: Option<String>,
+}
+
+impl Privilege {
+	pub fn detect(configured: Option<Escalation>) -> Self {
+		let escalation = if is_root() {
+			if configured.is_some_and(|e| e != Escalation::None) {
+				info!("Already running as root, not using privilege escalation");
+			}
+			Escalation::None
+		} else {
+			configured.unwrap_or(Escalation::Sudo)
+		};
+
+		Self {
+			escalation,
+			invoking_user: invoking_user(),
+		}
+	}
+
+	/// The user that user-level steps run as.
+	pub fn username(&self) -> Result<String> {
+		match self.invoking_user {
+			Some(ref user) => Ok(user.clone()),
+			None => Ok(std::env::var("USER")?),
+		}
+	}
+
+	/// Builds a command that runs `program` as root.
+	pub fn root_command(&self, program: &str) -> Command {
+		let mut cmd = match self.escalation {
+			// -n makes them fail instead of prompting for a password nobody is there to type
+			Escalation::Sudo | Escalation::Doas => {
+				let mut cmd = Command::new(self.escalation.name());
+				cmd.arg("-n");
+				cmd
+			}
+			Escalation::Run0 => Command::new("run0"),
+			Escalation::None => return Command::new(program),
+		};
+		cmd.arg(program);
+		cmd
+	}
+
+	/// Builds a command that runs `program` as `run_as`.
+	pub fn command(&self, run_as: &RunAs, program: &str) -> Command {
+		let user = match run_as {
+			RunAs::User => return self.user_command(program),
+			RunAs::Root => return self.root_command(program),
+			RunAs::Other(user) => user,
+		};
+
+		let mut cmd = match self.escalation {
+			Escalation::Sudo | Escalation::Doas => {
+				let mut cmd = Command::new(self.escalation.name());
+				cmd.args(["-n", "-u", user]);
+				cmd
+			}
+			Escalation::Run0 => {
+				let mut cmd = Command::new("run0");
+				cmd.arg(format!("--user={user}"));
+				cmd
+			}
+			Escalation::None => {
+				let mut cmd = Command::new("runuser");
+				cmd.args(["-u", user, "--"]);
+				cmd
+			}
+		};
+		cmd.arg(program);
+		cmd
+	}
+
+	/// Like `command`, but with extra environment variables. sudo, doas and run0 all reset the environment, so sudo and
+	/// run0 are told which variables to keep by name. doas can only be given values through `env` arguments, which any
+	/// user can read, so the variables in `secret` are left out with doas.
+	pub fn command_env(
+		&self,
+		run_as: &RunAs,
+		program: &str,
+		env: &BTreeMap<String, String>,
+		secret: &BTreeSet<String>,
+	) -> Command {
+		// runuser keeps the environment
+		if *run_as == RunAs::User || self.escalation == Escalation::None || env.is_empty() {
+			let mut cmd = self.command(run_as, program);
+			cmd.envs(env);
+			return cmd;
+		}
+
+		let mut cmd = Command::new(self.escalation.name());
+		match self.escalation {
+			Escalation::Sudo => {
+				let names = env.keys().map(String::as_str).collect::<Vec<_>>().join(",");
+				cmd.arg("-n").arg(format!("--preserve-env={names}"));
+				if let RunAs::Other(user) = run_as {
+					cmd.args(["-u", user]);
+				}
+				cmd.envs(env);
+			}
+			Escalation::Run0 => {
+				if let RunAs::Other(user) = run_as {
+					cmd.arg(format!("--user={user}"));
+				}
+				// Without a value run0 takes the variable from its own environment
+				cmd.args(env.keys().map(|key| format!("--setenv={key}")));
+				cmd.envs(env);
+			}
+			Escalation::Doas => {
+				cmd.arg("-n");
+				if let RunAs::Other(user) = run_as {
+					cmd.args(["-u", user]);
+				}
+				cmd.arg("env").args(
+					env.iter()
+						.filter(|(key, _)| !secret.contains(*key))
+						.map(|(key, value)| format!("{key}={value}")),
+				);
+			}
+			Escalation::None => unreachable!("commands aren't escalated without an escalation"),
+		}
+		cmd.arg(program);
+		cmd
+	}
+
+	/// The user commands for `run_as` run as, if that isn't the user mrow runs as. Root is left out since it can read
+	/// every file anyway.
+	pub fn other_user<'a>(&'a self, run_as: &'a RunAs) -> Option<&'a str> {
+		match run_as {
+			RunAs::User => self.invoking_user.as_deref(),
+			RunAs::Root => None,
+			RunAs::Other(user) => Some(user),
+		}
+	}
+
+	/// Builds a command that runs `program` as the user, dropping root if mrow was started through sudo or doas.
+	pub fn user_command(&self, program: &str) -> Command {
+		match self.invoking_user {
+			Some(ref user) => {
+				let mut cmd = Command::new("runuser");
+				cmd.args(["-u", user, "--", program]);
+				cmd
+			}
+			None => Command::new(program),
+		}
+	}
+
+	/// Asks for the password once at the start of the run. Returns the command's output if authentication failed.
+	///
+	/// Every later doas call uses `-n`, which only works if doas keeps the password with `persist`. That's checked
+	/// here so the run fails before any step does. run0 has no `-n`, polkit decides whether later calls ask again.
+	pub fn authenticate(&self) -> Result<Option<std::process::Output>> {
+		let output = match self.escalation {
+			Escalation::Sudo => Command::new("sudo").arg("-v").output()?,
+			Escalation::Doas | Escalation::Run0 => Command::new(self.escalation.name()).arg("true").output()?,
+			Escalation::None => return Ok(None),
+		};
+		if !output.status.success() {
+			return Ok(Some(output));
+		}
+
+		if self.escalation == Escalation::Doas && !Command::new("doas").args(["-n", "true"]).status()?.success() {
+			return Err(Error::DoasNotPersisted);
+		}
+		Ok(None)
+	}
+}
@@ -0,0 +1,320 @@
+use std::{
+	collections::HashMap,
+	io::{BufRead, Write},
+	process::{Command, Stdio},
+};
+
+use mlua::FromLua;
+
+use crate::{info, warn, Deserialize, Error, LazyLock, Lua, Path, PathBuf, Regex, Result};
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PromptKind {
+	#[default]
+	Text,
+	Secret,
+	Choice,
+	Confirm,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Prompt {
+	pub name: String,
+	#[serde(default)]
+	pub kind: PromptKind,
+	pub message: Option<String>,
+	pub default: Option<String>,
+	#[serde(default)]
+	pub choices: Vec<String>,
+	/// Remember the answer in the state directory so later runs don't ask again. Ignored for secrets.
+	#[serde(default)]
+	pub cache: bool,
+}
+
+impl<'lua> FromLua<'lua> for PromptKind {
+	fn from_lua(value: mlua::Value<'lua>, _lua: &'lua Lua) -> mlua::Result<Self> {
+		let Some(str) = value.as_str() else {
+			return Err(mlua::Error::FromLuaConversionError {
+				from: value.type_name(),
+				to: "PromptKind",
+				message: None,
+			});
+		};
+
+		Ok(match str {
+			"text" => PromptKind::Text,
+			"secret" => PromptKind::Secret,
+			"choice" => PromptKind::Choice,
+			"confirm" => PromptKind::Confirm,
+			v => {
+				return Err(mlua::Error::FromLuaConversionError {
+					from: value.type_name(),
+					to: "PromptKind",
+					message: Some(format!("Expected 'text', 'secret', 'choice' or 'confirm'. Got '{v}'")),
+				})
+			}
+		})
+	}
+}
+
+impl<'lua> FromLua<'lua> for Prompt {
+	fn from_lua(value: mlua::Value<'lua>, _lua: &'lua Lua) -> mlua::Result<Self> {
+		match value {
+			mlua::Value::Table(table) => Ok(Self {
+				name: table.get("name")?,
+				kind: table.get::<_, Option<PromptKind>>("kind")?.unwrap_or_default(),
+				message: table.get("message")?,
+				default: table.get("default")?,
+				choices: table.get::<_, Option<Vec<String>>>("choices")?.unwrap_or_default(),
+				cache: table.get::<_, Option<bool>>("cache")?.unwrap_or_default(),
+			}),
+			_ => Err(mlua::Error::FromLuaConversionError {
+				from: value.type_name(),
+				to: "Prompt",
+				message: None,
+			}),
+		}
+	}
+}
+
+#[derive(Debug, Clone)]
+struct Answer {
+	value: String,
+	secret: bool,
+}
+
+/// The environment variable an answer is passed to steps as.
+fn env_name(prompt: &str) -> String {
+	format!("MROW_VAR_{}", prompt.to_uppercase().replace('-', "_"))
+}
+
+/// Asks prompts and keeps their answers for the rest of the run.
+///
+/// Answers are passed to steps as `MROW_VAR_<NAME>` and non-secret answers can be used in TOML steps as
+/// `{{ name }}`.
+#[derive(Debug)]
+pub struct Prompter {
+	base_dir: String,
+	cache_path: Option<PathBuf>,
+	cache: toml::Table,
+	reprompt: bool,
+	answers: HashMap<String, Answer>,
+}
+
+impl Prompter {
+	pub fn new(base_dir: &Path, reprompt: bool) -> Result<Self> {
+		let cache_path = dirs::state_dir().map(|dir| dir.join("mrow").join("answers.toml"));
+		let cache = match cache_path {
+			Some(ref path) if path.exists() => {
+				toml::from_str(&std::fs::read_to_string(path)?).map_err(|err| Error::Toml(path.clone(), err))?
+			}
+			_ => toml::Table::new(),
+		};
+
+		Ok(Self {
+			base_dir: base_dir.to_string_lossy().into_owned(),
+			cache_path,
+			cache,
+			reprompt,
+			answers: HashMap::new(),
+		})
+	}
+
+	fn cached(&self, name: &str) -> Option<&str> {
+		self.cache
+			.get(&self.base_dir)
+			.and_then(|answers| answers.get(name))
+			.and_then(|answer| answer.as_str())
+	}
+
+	/// Asks `prompt` unless it was already answered this run or has a cached answer.
+	pub fn ask(&mut self, prompt: &Prompt) -> Result<String> {
+		if let Some(answer) = self.answers.get(&prompt.name) {
+			return Ok(answer.value.clone());
+		}
+
+		if prompt.kind == PromptKind::Choice && prompt.choices.is_empty() {
+			return Err(Error::InvalidPrompt(
+				prompt.name.clone(),
+				"Choice prompts need at least one choice.".into(),
+			));
+		}
+
+		let secret = prompt.kind == PromptKind::Secret;
+		let cache = prompt.cache && !secret;
+		let value = match self.cached(&prompt.name) {
+			Some(value) if cache && !self.reprompt => {
+				info!("Using cached answer for '{}'", prompt.name);
+				value.to_string()
+			}
+			_ => read_answer(prompt)?,
+		};
+
+		if cache {
+			if let toml::Value::Table(answers) = self
+				.cache
+				.entry(self.base_dir.clone())
+				.or_insert_with(|| toml::Value::Table(toml::Table::new()))
+			{
+				answers.insert(prompt.name.clone(), toml::Value::String(value.clone()));
+			}
+		}
+
+		self.answers.insert(
+			prompt.name.clone(),
+			Answer {
+				value: value.clone(),
+				secret,
+			},
+		);
+		Ok(value)
+	}
+
+	/// The answers as `MROW_VAR_<NAME>` environment variables, for the steps to be run with.
+	pub fn env(&self) -> impl Iterator<Item = (String, String)> + '_ {
+		self.answers
+			.iter()
+			.map(|(name, answer)| (env_name(name), answer.value.clone()))
+	}
+
+	/// The names of the variables in `env` holding secret answers.
+	pub fn secret_env(&self) -> impl Iterator<Item = String> + '_ {
+		self.answers
+			.iter()
+			.filter(|(_, answer)| answer.secret)
+			.map(|(name, _)| env_name(name))
+	}
+
+	/// Writes cached answers back to the state directory.
+	pub fn save(&self) -> Result<()> {
+		let Some(ref path) = self.cache_path else {
+			return Ok(());
+		};
+		if self.cache.is_empty() {
+			return Ok(());
+		}
+
+		if let Some(parent) = path.parent() {
+			std::fs::create_dir_all(parent)?;
+		}
+		std::fs::write(path, self.cache.to_string())?;
+		Ok(())
+	}
+
+	/// Replaces every `{{ name }}` in `str` with the answer to the prompt called `name`.
+	pub fn substitute(&self, str: &str) -> std::result::Result<String, String> {
+		static VARIABLE_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+			Regex::new(r"\{\{\s*([A-Za-z0-9_-]+)\s*\}\}")
+				.unwrap_or_else(|_| unreachable!("regex should always be valid"))
+		});
+
+		let mut error = None;
+		let replaced = VARIABLE_REGEX.replace_all(str, |captures: &regex::Captures| {
+			let name = &captures[1];
+			match self.answers.get(name) {
+				Some(Answer { secret: true, .. }) => {
+					error = Some(format!(
+						"'{name}' is a secret, read it from $MROW_VAR_{} instead",
+						name.to_uppercase().replace('-', "_")
+					));
+					String::new()
+				}
+				Some(Answer { value, .. }) => value.clone(),
+				None => {
+					error = Some(format!("Unknown variable '{name}'"));
+					String::new()
+				}
+			}
+		});
+
+		match error {
+			Some(error) => Err(error),
+			None => Ok(replaced.into_owned()),
+		}
+	}
+}
+
+/// Asks a yes or no question that isn't a configured prompt, defaulting to no.
+pub fn confirm(message: &str) -> Result<bool> {
+	let prompt = Prompt {
+		name: String::new(),
+		kind: PromptKind::Confirm,
+		message: Some(message.into()),
+		default: Some("n".into()),
+		choices: vec![],
+		cache: false,
+	};
+	Ok(read_answer(&prompt)? == "true")
+}
+
+fn read_line() -> Result<String> {
+	let mut line = String::new();
+	if std::io::stdin().lock().read_line(&mut line)? == 0 {
+		return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+	}
+	Ok(line.trim().to_string())
+}
+
+fn read_answer(prompt: &Prompt) -> Result<String> {
+	let message = prompt.message.as_deref().unwrap_or(&prompt.name);
+
+	loop {
+		match prompt.kind {
+			PromptKind::Text => print!("{message}"),
+			PromptKind::Secret => print!("{message} (hidden)"),
+			PromptKind::Choice => {
+				println!("{message}");
+				for (i, choice) in prompt.choices.iter().enumerate() {
+					println!("  {}) {choice}", i + 1);
+				}
+				print!("Choice");
+			}
+			PromptKind::Confirm => print!("{message} [y/n]"),
+		}
+		if let Some(ref default) = prompt.default {
+			print!(" [default: {default}]");
+		}
+		print!(": ");
+		std::io::stdout().flush()?;
+
+		let answer = if prompt.kind == PromptKind::Secret {
+			// Fails harmlessly when stdin isn't a terminal
+			Command::new("stty").arg("-echo").stderr(Stdio::null()).status()?;
+			let answer = read_line();
+			Command::new("stty").arg("echo").stderr(Stdio::null()).status()?;
+			println!();
+			answer?
+		} else {
+			read_line()?
+		};
+
+		let answer = match (answer.is_empty(), &prompt.default) {
+			(true, Some(default)) => default.clone(),
+			_ => answer,
+		};
+
+		match prompt.kind {
+			PromptKind::Text | PromptKind::Secret => return Ok(answer),
+			PromptKind::Choice => {
+				if let Some(choice) = answer
+					.parse::<usize>()
+					.ok()
+					.and_then(|i| i.checked_sub(1))
+					.and_then(|i| prompt.choices.get(i))
+				{
+					return Ok(choice.clone());
+				}
+				if prompt.choices.contains(&answer) {
+					return Ok(answer);
+				}
+				warn!("'{answer}' is not one of the choices");
+			}
+			PromptKind::Confirm => match answer.to_lowercase().as_str() {
+				"y" | "yes" | "true" => return Ok("true".into()),
+				"n" | "no" | "false" => return Ok("false".into()),
+				_ => warn!("Answer 'y' or 'n'"),
+			},
+		}
+	}
+}
@@ -0,0 +1,204 @@
+use std::{
+	fmt,
+	io::Write,
+	os::unix::fs::{DirBuilderExt, OpenOptionsExt},
+	process::{Command, Output, Stdio},
+};
+
+use crate::{debug, info, Context, Error, Path, PathBuf, Result, RunAs};
+
+/// A decrypted age identity. Never printed, not even in debug output.
+pub struct Identity(Vec<u8>);
+
+impl fmt::Debug for Identity {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str("Identity(<redacted>)")
+	}
+}
+
+pub fn run_with_stdin(cmd: &mut Command, input: &[u8]) -> std::io::Result<Output> {
+	cmd.stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped());
+	let mut child = cmd.spawn()?;
+	if let Some(mut stdin) = child.stdin.take() {
+		stdin.write_all(input)?;
+	}
+	child.wait_with_output()
+}
+
+fn check_age(path: &Path, output: &Output) -> Result<()> {
+	if output.status.success() {
+		Ok(())
+	} else {
+		Err(Error::Age(
+			path.to_path_buf(),
+			String::from_utf8_lossy(&output.stderr).into_owned(),
+		))
+	}
+}
+
+/// Reads an age identity file.
+///
+/// If the identity file is itself passphrase-encrypted, age is asked to decrypt it once and the passphrase prompt is
+/// shown on the terminal. The plaintext identity is then kept in memory for the rest of the run.
+pub fn load_identity(path: &Path) -> Result<Identity> {
+	let contents = std::fs::read(path)?;
+	if !contents.starts_with(b"age-encryption.org/v1") && !contents.starts_with(b"-----BEGIN AGE ENCRYPTED FILE-----") {
+		return Ok(Identity(contents));
+	}
+
+	info!(
+		"Age identity '{}' is passphrase protected, enter its passphrase",
+		path.to_string_lossy()
+	);
+	let output = Command::new("age")
+		.arg("--decrypt")
+		.arg(path)
+		.stdin(Stdio::inherit())
+		.stderr(Stdio::inherit())
+		.output()?;
+	check_age(path, &output)?;
+	Ok(Identity(output.stdout))
+}
+
+/// Returns the configured recipients, or the recipient belonging to `identity` if none are configured.
+pub fn recipients(identity_path: &Path, identity: &Identity, configured: &[String]) -> Result<Vec<String>> {
+	if !configured.is_empty() {
+		return Ok(configured.to_vec());
+	}
+
+	let output = run_with_stdin(Command::new("age-keygen").arg("-y"), &identity.0)?;
+	check_age(identity_path, &output)?;
+	Ok(String::from_utf8_lossy(&output.stdout)
+		.lines()
+		.map(str::trim)
+		.filter(|l| !l.is_empty())
+		.map(ToString::to_string)
+		.collect())
+}
+
+pub fn decrypt(identity: &Identity, path: &Path) -> Result<Vec<u8>> {
+	let mut cmd = Command::new("age");
+	cmd.args(["--decrypt", "--identity", "-"]).arg(path);
+	let output = run_with_stdin(&mut cmd, &identity.0)?;
+	check_age(path, &output)?;
+	Ok(output.stdout)
+}
+
+pub fn encrypt(recipients: &[String], plaintext: &[u8], out: &Path) -> Result<()> {
+	let mut cmd = Command::new("age");
+	cmd.arg("--encrypt");
+	for recipient in recipients {
+		cmd.args(["--recipient", recipient]);
+	}
+	cmd.arg("--output").arg(out);
+	let output = run_with_stdin(&mut cmd, plaintext)?;
+	check_age(out, &output)
+}
+
+/// Decrypts `from` and writes the plaintext to `to` with mode 600, unless `to` already has that content. Returns
+/// whether `to` was written.
+///
+/// The plaintext only ever goes through a pipe, it is never logged or passed as an argument.
+pub fn install_file(ctx: &Context, owner: &Path, run_as: &RunAs, from: &Path, to: &Path) -> Result<bool> {
+	let mut create = ctx.privilege.command(run_as, "install");
+	create.args(["-m", "600", "/dev/null"]).arg(to);
+	let mut write = ctx.privilege.command(run_as, "tee");
+	write.arg(to).stdout(Stdio::null());
+
+	if ctx.debug {
+		debug!("age --decrypt --identity - {}", from.to_string_lossy());
+		debug!("{create:?}");
+		debug!("{write:?}");
+		return Ok(true);
+	}
+
+	let Some(ref identity) = ctx.identity else {
+		return Err(Error::NoAgeIdentity);
+	};
+	let plaintext = decrypt(identity, from)?;
+	// An unreadable destination, e.g. one only root can read, is written anyway
+	if std::fs::read(to).is_ok_and(|existing| existing == plaintext) {
+		return Ok(false);
+	}
+
+	let output = create.output()?;
+	if !output.status.success() {
+		return Err(Error::StepFailed(
+			owner.to_string_lossy().into_owned(),
+			String::from_utf8_lossy(&output.stderr).into_owned(),
+		));
+	}
+
+	let output = run_with_stdin(&mut write, &plaintext)?;
+	if !output.status.success() {
+		return Err(Error::StepFailed(
+			owner.to_string_lossy().into_owned(),
+			String::from_utf8_lossy(&output.stderr).into_owned(),
+		));
+	}
+
+	Ok(true)
+}
+
+/// Encrypts `file` to `<file>.age`. The plaintext file is left in place.
+pub fn encrypt_file(recipients: &[String], file: &Path) -> Result<PathBuf> {
+	let mut out = file.as_os_str().to_os_string();
+	out.push(".age");
+	let out = PathBuf::from(out);
+
+	encrypt(recipients, &std::fs::read(file)?, &out)?;
+	Ok(out)
+}
+
+/// Decrypts `file` into a private temporary directory, opens it in `$EDITOR` and re-encrypts it if it was changed.
+///
+/// If `file` doesn't exist yet the editor starts with an empty file.
+pub fn edit_file(identity: &Identity, recipients: &[String], file: &Path) -> Result<()> {
+	let plaintext = if file.exists() {
+		decrypt(identity, file)?
+	} else {
+		vec![]
+	};
+
+	let temp_dir = std::env::temp_dir().join(format!("mrow-secret-{}", std::process::id()));
+	std::fs::DirBuilder::new().mode(0o700).create(&temp_dir)?;
+
+	let result = (|| {
+		let name = file
+			.file_stem()
+			.unwrap_or_else(|| unreachable!("linux requires that files have names"));
+		let temp_file = temp_dir.join(name);
+		std::fs::OpenOptions::new()
+			.write(true)
+			.create_new(true)
+			.mode(0o600)
+			.open(&temp_file)?
+			.write_all(&plaintext)?;
+
+		let editor = std::env::var("EDITOR").unwrap_or_else(|_| "vi".into());
+		let mut editor = editor.split_whitespace();
+		let status = Command::new(editor.next().unwrap_or("vi"))
+			.args(editor)
+			.arg(&temp_file)
+			.status()?;
+		if !status.success() {
+			return Err(Error::Age(
+				file.to_path_buf(),
+				"Editor exited unsuccessfully, not saving".into(),
+			));
+		}
+
+		let edited = std::fs::read(&temp_file)?;
+		if edited == plaintext {
+			info!("'{}' unchanged", file.to_string_lossy());
+			return Ok(());
+		}
+
+		encrypt(recipients, &edited, file)?;
+		info!("Saved '{}'", file.to_string_lossy());
+		Ok(())
+	})();
+
+	std::fs::remove_dir_all(&temp_dir)?;
+	result
+}
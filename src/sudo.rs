@@ -0,0 +1,75 @@
+use std::{
+	process::{Command, Stdio},
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		mpsc::{self, RecvTimeoutError, Sender},
+		Arc,
+	},
+	thread::JoinHandle,
+	time::Duration,
+};
+
+use crate::{error, Error, Result};
+
+/// sudo's default `timestamp_timeout` is 5 minutes, refreshing every minute leaves plenty of room.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Keeps the sudo timestamp fresh in the background until it's dropped.
+pub struct Keepalive {
+	stop: Option<Sender<()>>,
+	handle: Option<JoinHandle<()>>,
+	expired: Arc<AtomicBool>,
+}
+
+impl Keepalive {
+	pub fn start() -> Self {
+		let (stop, stopped) = mpsc::channel();
+		let expired = Arc::new(AtomicBool::new(false));
+
+		let handle = {
+			let expired = expired.clone();
+			std::thread::spawn(move || {
+				while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(REFRESH_INTERVAL) {
+					// -n makes sudo fail instead of prompting for a password nobody is there to type
+					let refreshed = Command::new("sudo")
+						.args(["-n", "-v"])
+						.stdin(Stdio::null())
+						.stdout(Stdio::null())
+						.stderr(Stdio::null())
+						.status()
+						.is_ok_and(|status| status.success());
+
+					if !refreshed {
+						error!("Failed to refresh sudo credentials");
+						expired.store(true, Ordering::SeqCst);
+						break;
+					}
+				}
+			})
+		};
+
+		Self {
+			stop: Some(stop),
+			handle: Some(handle),
+			expired,
+		}
+	}
+
+	pub fn check(&self) -> Result<()> {
+		if self.expired.load(Ordering::SeqCst) {
+			Err(Error::SudoExpired)
+		} else {
+			Ok(())
+		}
+	}
+}
+
+impl Drop for Keepalive {
+	fn drop(&mut self) {
+		// Dropping the sender wakes the thread up immediately instead of waiting out the interval
+		drop(self.stop.take());
+		if let Some(handle) = self.handle.take() {
+			let _ = handle.join();
+		}
+	}
+}